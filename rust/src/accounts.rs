@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::fmt::Write;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use base64::{encode, decode};
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+
+use crate::model::Id;
+use crate::utils::*;
+
+const HASH_ROUNDS : u32 = 100000;
+const HASH_LENGTH : usize = 32;
+// duel counts change at each duel, they are not worth a write each time
+const DUEL_COUNTS_SAVE_INTERVAL_MS : u64 = 60000;
+// set in the ids of the local accounts and never in the backend user ids, so that they cannot collide
+pub const LOCAL_ID_FLAG : Id = 1 << 31;
+
+#[derive(Debug, Clone)]
+pub struct Account {
+  pub id    : Id,
+  pub name  : String,
+  pub duels : u32,
+  credentials : Credentials
+}
+
+// hashing is slow on purpose, it is done off the handler thread
+#[derive(Clone)]
+pub struct Credentials {
+  salt : Vec<u8>,
+  hash : Vec<u8>
+}
+
+impl Debug for Credentials {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+    f.write_str(REDACTED)
+  }
+}
+
+impl Credentials {
+
+  pub fn new(password : &str) -> Credentials {
+    let salt = rand::random::<[u8; 16]>().to_vec();
+    Credentials {
+      hash : hash_password(password, &salt),
+      salt
+    }
+  }
+
+  pub fn matches(&self, password : &str) -> bool {
+    constant_time_eq(&hash_password(password, &self.salt), &self.hash)
+  }
+}
+
+// base64 name:id:duels
+impl Display for Account {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+    write!(f, "{}:{}:{}", encode(self.name.as_bytes()), self.id, self.duels)
+  }
+}
+
+// accounts are persisted as one line per account : base64 name;id;duels;base64 salt;base64 hash.
+// the file is written off the handler thread from snapshots taken at each tick
+#[derive(Debug)]
+pub struct Accounts {
+  path     : PathBuf,
+  accounts : HashMap<String, Account>, // by name
+  names    : HashMap<Id, String>, // by id
+  unsaved_accounts : bool, // registered since the last snapshot, saved on the next one
+  unsaved_duels    : bool, // duel counts changed since the last snapshot, saved less often
+  last_snapshot    : u64, // in ms
+  generation       : u64, // of the last snapshot
+  written          : Arc<Written>
+}
+
+// what the writes of the snapshots left on disk
+#[derive(Debug, Default)]
+struct Written {
+  generation : Mutex<u64>, // of the last snapshot written, an older one is not written after it
+  failed     : AtomicBool // the accounts are to be saved again, apart so that the handler never waits for a write
+}
+
+// the file contents at a point in time
+pub struct AccountsSnapshot {
+  path       : PathBuf,
+  contents   : String,
+  generation : u64,
+  written    : Arc<Written>
+}
+
+impl Accounts {
+
+  // a line that cannot be read is skipped, failing to read the file fails the load
+  pub fn load(path : PathBuf) -> BasicResult<Accounts> {
+    let mut accounts = Accounts::empty(path);
+    if accounts.path.exists() {
+      let file = File::open(&accounts.path)?;
+      for line in BufReader::new(file).lines() {
+        let line = line?;
+        match parse_account(&line) {
+          Ok(account) => accounts.insert(account),
+          Err(err) => warn!("Ignored account line {} : {}", line, err)
+        }
+      }
+      info!("Loaded {} accounts from {:?}", accounts.accounts.len(), accounts.path);
+    }
+    Ok(accounts)
+  }
+
+  pub fn empty(path : PathBuf) -> Accounts {
+    Accounts {
+      path,
+      accounts : HashMap::new(),
+      names    : HashMap::new(),
+      unsaved_accounts : false,
+      unsaved_duels    : false,
+      last_snapshot    : 0,
+      generation       : 0,
+      written          : Arc::new(Written::default())
+    }
+  }
+
+  pub fn exists(&self, name : &str) -> bool {
+    self.accounts.contains_key(name)
+  }
+
+  pub fn credentials(&self, name : &str) -> Option<Credentials> {
+    self.accounts.get(name).map(|account| account.credentials.clone())
+  }
+
+  pub fn get(&self, name : &str) -> Option<&Account> {
+    self.accounts.get(name)
+  }
+
+  pub fn register(&mut self, name : &str, credentials : Credentials) -> BasicResult<Account> {
+    if self.exists(name) {
+      return Err(From::from(format!("Account {} already exists", name)))
+    }
+    let mut id = rand::random::<Id>() | LOCAL_ID_FLAG;
+    while self.names.contains_key(&id) {
+      id = rand::random::<Id>() | LOCAL_ID_FLAG;
+    }
+    let account = Account {
+      id,
+      name  : name.to_string(),
+      duels : 0,
+      credentials
+    };
+    self.insert(account.clone());
+    self.unsaved_accounts = true;
+    Ok(account)
+  }
+
+  pub fn add_duel(&mut self, id : Id) {
+    let account = self.names.get(&id).and_then(|name| self.accounts.get_mut(name));
+    if let Some(account) = account {
+      account.duels += 1;
+      self.unsaved_duels = true;
+    }
+  }
+
+  // the contents to write when there is something worth saving at now
+  pub fn snapshot(&mut self, now : u64) -> BasicResult<Option<AccountsSnapshot>> {
+    if self.written.failed.swap(false, Ordering::SeqCst) {
+      self.unsaved_accounts = true;
+    }
+    let duels_due = self.unsaved_duels && now.saturating_sub(self.last_snapshot) >= DUEL_COUNTS_SAVE_INTERVAL_MS;
+    if !self.unsaved_accounts && !duels_due {
+      return Ok(None)
+    }
+    let mut contents = String::new();
+    for account in self.accounts.values() {
+      writeln!(contents, "{};{};{};{};{}", encode(account.name.as_bytes()), account.id, account.duels,
+        encode(&account.credentials.salt), encode(&account.credentials.hash))?;
+    }
+    self.unsaved_accounts = false;
+    self.unsaved_duels = false;
+    self.last_snapshot = now;
+    self.generation += 1;
+    Ok(Some(AccountsSnapshot {
+      path       : self.path.clone(),
+      contents,
+      generation : self.generation,
+      written    : self.written.clone()
+    }))
+  }
+
+  fn insert(&mut self, account : Account) {
+    self.names.insert(account.id, account.name.clone());
+    self.accounts.insert(account.name.clone(), account);
+  }
+
+}
+
+impl AccountsSnapshot {
+
+  // blocks on the disk, the lock keeps the writes in order
+  pub fn write(self) -> BasicResult<()> {
+    let mut generation = box_err(self.written.generation.lock())?;
+    if self.generation <= *generation {
+      return Ok(()) // a later snapshot is already written
+    }
+    match write_atomically(&self.path, &self.contents) {
+      Ok(()) => {
+        *generation = self.generation;
+        Ok(())
+      },
+      Err(err) => {
+        self.written.failed.store(true, Ordering::SeqCst);
+        Err(From::from(err))
+      }
+    }
+  }
+}
+
+fn parse_account(line : &str) -> BasicResult<Account> {
+  let fields : Vec<&str> = line.split(';').collect();
+  if fields.len() != 5 {
+    return Err(From::from(format!("{} fields instead of 5", fields.len())))
+  }
+  Ok(Account {
+    name  : String::from_utf8(decode(fields[0])?)?,
+    id    : fields[1].parse()?,
+    duels : fields[2].parse()?,
+    credentials : Credentials {
+      salt : decode(fields[3])?,
+      hash : decode(fields[4])?
+    }
+  })
+}
+
+fn hash_password(password : &str, salt : &[u8]) -> Vec<u8> {
+  let mut hash = vec![0u8; HASH_LENGTH];
+  pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, HASH_ROUNDS, &mut hash);
+  hash
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // unhashed, hashing takes too long for tests
+  fn credentials() -> Credentials {
+    Credentials { salt : vec![1, 2], hash : vec![3, 4] }
+  }
+
+  #[test]
+  fn bad_lines_are_skipped_and_kept_out_of_the_next_save() {
+    let dir = TempDir::new();
+    let path = dir.0.join("accounts");
+    let valid = format!("{};{};3;AQI=;AwQ=", encode("toto".as_bytes()), LOCAL_ID_FLAG | 7);
+    std::fs::write(&path, format!("{}\n!!bad\n{};x;0;;\n", valid, encode("titi".as_bytes()))).unwrap();
+    let mut accounts = Accounts::load(path.clone()).unwrap();
+    assert_eq!(accounts.get("toto").unwrap().duels, 3);
+    assert!(!accounts.exists("titi"));
+
+    accounts.register("newbie", credentials()).unwrap();
+    accounts.snapshot(0).unwrap().unwrap().write().unwrap();
+    let reloaded = Accounts::load(path).unwrap();
+    assert!(reloaded.exists("toto"));
+    assert!(reloaded.exists("newbie"));
+    assert_eq!(reloaded.accounts.len(), 2);
+  }
+
+  #[test]
+  fn duel_counts_are_saved_less_often_than_new_accounts() {
+    let dir = TempDir::new();
+    let mut accounts = Accounts::load(dir.0.join("accounts")).unwrap();
+    assert!(accounts.snapshot(0).unwrap().is_none());
+    let account = accounts.register("toto", credentials()).unwrap();
+    assert!(accounts.snapshot(1000).unwrap().is_some());
+    accounts.add_duel(account.id);
+    assert!(accounts.snapshot(2000).unwrap().is_none());
+    assert!(accounts.snapshot(1000 + DUEL_COUNTS_SAVE_INTERVAL_MS).unwrap().is_some());
+    assert!(accounts.snapshot(u64::MAX).unwrap().is_none());
+  }
+
+  #[test]
+  fn older_snapshot_is_not_written_over_a_newer_one() {
+    let dir = TempDir::new();
+    let path = dir.0.join("accounts");
+    let mut accounts = Accounts::load(path.clone()).unwrap();
+    accounts.register("toto", credentials()).unwrap();
+    let older = accounts.snapshot(0).unwrap().unwrap();
+    accounts.register("titi", credentials()).unwrap();
+    accounts.snapshot(0).unwrap().unwrap().write().unwrap();
+    older.write().unwrap();
+    assert!(Accounts::load(path).unwrap().exists("titi"));
+  }
+
+  #[test]
+  fn failed_write_is_retried() {
+    let dir = TempDir::new();
+    let mut accounts = Accounts::load(dir.0.join("missing").join("accounts")).unwrap();
+    accounts.register("toto", credentials()).unwrap();
+    assert!(accounts.snapshot(0).unwrap().unwrap().write().is_err());
+    assert!(accounts.snapshot(0).unwrap().is_some());
+  }
+
+  #[test]
+  fn unreadable_file_fails_the_load() {
+    let dir = TempDir::new();
+    // a directory cannot be read as lines
+    assert!(Accounts::load(dir.0.clone()).is_err());
+  }
+}
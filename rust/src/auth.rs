@@ -0,0 +1,93 @@
+use base64::decode;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::model::Id;
+use crate::utils::*;
+
+#[derive(Debug)]
+pub struct Token {
+  pub user_id : Id,
+  pub expiry  : i64, // unix time in seconds
+  pub name    : String
+}
+
+// base64 payload.base64 hmac sha256 of the payload, where payload is user id:expiry:name
+pub fn verify_token(token : &str, key : &[u8]) -> BasicResult<Token> {
+  let (payload_b64, signature_b64) = match token.find('.') {
+    Some(i) => (&token[..i], &token[i+1..]),
+    None => return Err(From::from("Malformed token"))
+  };
+  let payload = decode(payload_b64)?;
+  let signature = decode(signature_b64)?;
+  let mut mac = box_err(Hmac::<Sha256>::new_from_slice(key))?;
+  mac.update(&payload);
+  box_err(mac.verify_slice(&signature))?;
+
+  let payload_str = String::from_utf8(payload)?;
+  let fields : Vec<&str> = payload_str.splitn(3, ':').collect();
+  if fields.len() != 3 || fields[2].is_empty() {
+    return Err(From::from("Malformed token payload"))
+  }
+  let token = Token {
+    user_id : fields[0].parse()?,
+    expiry  : fields[1].parse()?,
+    name    : fields[2].to_string()
+  };
+  if token.expiry < time::get_time().sec {
+    Err(From::from(format!("Token of {} expired", token.user_id)))
+  } else {
+    Ok(token)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use base64::encode;
+  use super::*;
+
+  const KEY : &[u8] = b"key";
+
+  fn sign(payload : &str, key : &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(payload.as_bytes());
+    format!("{}.{}", encode(payload.as_bytes()), encode(&mac.finalize().into_bytes()))
+  }
+
+  fn in_a_minute() -> i64 {
+    time::get_time().sec + 60
+  }
+
+  #[test]
+  fn valid_token_gives_the_user() {
+    let token = verify_token(&sign(&format!("7:{}:to:to", in_a_minute()), KEY), KEY).unwrap();
+    assert_eq!(token.user_id, 7);
+    assert_eq!(token.name, "to:to");
+  }
+
+  #[test]
+  fn bad_signature_is_refused() {
+    let payload = format!("7:{}:toto", in_a_minute());
+    assert!(verify_token(&sign(&payload, b"other key"), KEY).is_err());
+    // a payload swapped under a valid signature
+    let signed = sign(&payload, KEY);
+    let signature = &signed[signed.find('.').unwrap()..];
+    let forged = format!("{}{}", encode(format!("8:{}:toto", in_a_minute()).as_bytes()), signature);
+    assert!(verify_token(&forged, KEY).is_err());
+  }
+
+  #[test]
+  fn expired_token_is_refused() {
+    let payload = format!("7:{}:toto", time::get_time().sec - 1);
+    assert!(verify_token(&sign(&payload, KEY), KEY).is_err());
+  }
+
+  #[test]
+  fn malformed_token_is_refused() {
+    assert!(verify_token("no separator", KEY).is_err());
+    assert!(verify_token("!!.!!", KEY).is_err());
+    for payload in ["7:toto", &format!("7:{}:", in_a_minute()), &format!("x:{}:toto", in_a_minute()), "7:soon:toto"].iter() {
+      assert!(verify_token(&sign(payload, KEY), KEY).is_err(), "accepted {}", payload);
+    }
+  }
+}
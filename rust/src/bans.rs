@@ -0,0 +1,270 @@
+use std::fmt::{self, Display, Formatter, Write};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use crate::model::Id;
+use crate::utils::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BanTarget {
+  Account(Id),
+  Network(IpAddr, u8) // address, prefix length
+}
+
+impl BanTarget {
+
+  // an id, an ip or an ip/prefix
+  pub fn parse(s : &str) -> BasicResult<BanTarget> {
+    if let Ok(id) = s.parse() {
+      return Ok(BanTarget::Account(id))
+    }
+    let (addr_str, prefix_option) = match s.find('/') {
+      Some(i) => (&s[..i], Some(&s[i+1..])),
+      None => (s, None)
+    };
+    let addr : IpAddr = addr_str.parse()?;
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix_option {
+      Some(p) => p.parse()?,
+      None => max_prefix
+    };
+    if prefix > max_prefix {
+      Err(From::from(format!("Invalid prefix length {}", prefix)))
+    } else {
+      Ok(BanTarget::Network(addr, prefix))
+    }
+  }
+
+  pub fn contains(&self, ip : &IpAddr) -> bool {
+    match (self, ip) {
+      (&BanTarget::Network(IpAddr::V4(ref net), prefix), IpAddr::V4(ip)) => {
+        let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix as u32) };
+        u32::from(*net) & mask == u32::from(*ip) & mask
+      },
+      (&BanTarget::Network(IpAddr::V6(ref net), prefix), IpAddr::V6(ip)) => {
+        let mask = if prefix == 0 { 0 } else { u128::MAX << (128 - prefix as u32) };
+        u128::from(*net) & mask == u128::from(*ip) & mask
+      },
+      _ => false
+    }
+  }
+}
+
+impl Display for BanTarget {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+    match *self {
+      BanTarget::Account(id) => write!(f, "{}", id),
+      BanTarget::Network(addr, prefix) => write!(f, "{}/{}", addr, prefix)
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ban {
+  pub target : BanTarget,
+  pub expiry : Option<i64> // unix time in seconds
+}
+
+impl Ban {
+
+  fn is_active(&self, now : i64) -> bool {
+    self.expiry.map(|expiry| expiry > now).unwrap_or(true)
+  }
+}
+
+// bans are persisted as one line per ban : target;expiry, where expiry is 0 for a permanent ban
+#[derive(Debug)]
+pub struct Bans {
+  path : PathBuf,
+  bans : Vec<Ban>
+}
+
+impl Bans {
+
+  // a line that cannot be read is skipped, failing to read the file fails the load
+  pub fn load(path : PathBuf) -> BasicResult<Bans> {
+    let mut bans = Bans::empty(path);
+    if bans.path.exists() {
+      let file = File::open(&bans.path)?;
+      for line in BufReader::new(file).lines() {
+        let line = line?;
+        match parse_ban(&line) {
+          Ok(ban) => bans.bans.push(ban),
+          Err(err) => warn!("Ignored ban line {} : {}", line, err)
+        }
+      }
+      info!("Loaded {} bans from {:?}", bans.bans.len(), bans.path);
+    }
+    Ok(bans)
+  }
+
+  pub fn empty(path : PathBuf) -> Bans {
+    Bans {
+      path,
+      bans : Vec::new()
+    }
+  }
+
+  pub fn add(&mut self, target : BanTarget, duration_secs : Option<i64>) -> BasicResult<()> {
+    let now = time::get_time().sec;
+    let expiry = match duration_secs {
+      Some(d) => match now.checked_add(d) {
+        Some(expiry) if d > 0 => Some(expiry),
+        _ => return Err(From::from(format!("Invalid ban duration {}", d)))
+      },
+      None => None
+    };
+    self.bans.retain(|ban| ban.target != target && ban.is_active(now));
+    self.bans.push(Ban { target, expiry });
+    self.save()
+  }
+
+  pub fn remove(&mut self, target : &BanTarget) -> BasicResult<bool> {
+    let len = self.bans.len();
+    self.bans.retain(|ban| ban.target != *target);
+    if self.bans.len() != len {
+      self.save()?;
+      Ok(true)
+    } else {
+      Ok(false)
+    }
+  }
+
+  pub fn is_id_banned(&self, id : Id) -> bool {
+    let now = time::get_time().sec;
+    self.bans.iter().any(|ban| ban.target == BanTarget::Account(id) && ban.is_active(now))
+  }
+
+  pub fn is_ip_banned(&self, ip : &IpAddr) -> bool {
+    let now = time::get_time().sec;
+    self.bans.iter().any(|ban| ban.target.contains(ip) && ban.is_active(now))
+  }
+
+  pub fn active_bans(&self) -> Vec<Ban> {
+    let now = time::get_time().sec;
+    self.bans.iter().filter(|ban| ban.is_active(now)).cloned().collect()
+  }
+
+  fn save(&self) -> BasicResult<()> {
+    let mut contents = String::new();
+    for ban in self.bans.iter() {
+      writeln!(contents, "{};{}", ban.target, ban.expiry.unwrap_or(0))?;
+    }
+    Ok(write_atomically(&self.path, &contents)?)
+  }
+}
+
+fn parse_ban(line : &str) -> BasicResult<Ban> {
+  let fields : Vec<&str> = line.split(';').collect();
+  if fields.len() != 2 {
+    return Err(From::from(format!("{} fields instead of 2", fields.len())))
+  }
+  let expiry : i64 = fields[1].parse()?;
+  Ok(Ban {
+    target : BanTarget::parse(fields[0])?,
+    expiry : if expiry == 0 { None } else { Some(expiry) }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn network(s : &str) -> BanTarget {
+    BanTarget::parse(s).unwrap()
+  }
+
+  fn ip(s : &str) -> IpAddr {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn zero_prefix_contains_every_address_of_its_family() {
+    let target = network("10.0.0.1/0");
+    assert!(target.contains(&ip("192.168.1.1")));
+    assert!(target.contains(&ip("255.255.255.255")));
+    assert!(!target.contains(&ip("::1")));
+  }
+
+  #[test]
+  fn full_prefix_contains_only_its_address() {
+    let target = network("10.0.0.1");
+    assert_eq!(target, BanTarget::Network(ip("10.0.0.1"), 32));
+    assert!(target.contains(&ip("10.0.0.1")));
+    assert!(!target.contains(&ip("10.0.0.2")));
+  }
+
+  #[test]
+  fn ipv4_prefix_matches_its_network() {
+    let target = network("192.168.1.0/24");
+    assert!(target.contains(&ip("192.168.1.200")));
+    assert!(!target.contains(&ip("192.168.2.1")));
+  }
+
+  #[test]
+  fn ipv6_prefix_matches_its_network() {
+    let target = network("2001:db8::/32");
+    assert!(target.contains(&ip("2001:db8:1::1")));
+    assert!(!target.contains(&ip("2001:db9::1")));
+    assert!(!target.contains(&ip("32.1.13.184")));
+    assert!(network("::/0").contains(&ip("::1")));
+    assert!(network("::1/128").contains(&ip("::1")));
+    assert!(!network("::1/128").contains(&ip("::2")));
+  }
+
+  #[test]
+  fn invalid_targets_are_refused() {
+    assert!(BanTarget::parse("10.0.0.1/33").is_err());
+    assert!(BanTarget::parse("::1/129").is_err());
+    assert!(BanTarget::parse("not an ip").is_err());
+  }
+
+  #[test]
+  fn bad_lines_are_skipped_and_kept_out_of_the_next_save() {
+    let dir = TempDir::new();
+    let path = dir.0.join("bans");
+    std::fs::write(&path, "10.0.0.0/8;0\n!!bad;0\n7;soon\n").unwrap();
+    let mut bans = Bans::load(path.clone()).unwrap();
+    assert!(bans.is_ip_banned(&ip("10.1.2.3")));
+    assert!(!bans.is_id_banned(7));
+
+    bans.add(BanTarget::Account(8), None).unwrap();
+    let reloaded = Bans::load(path).unwrap();
+    assert!(reloaded.is_ip_banned(&ip("10.1.2.3")));
+    assert!(reloaded.is_id_banned(8));
+    assert_eq!(reloaded.active_bans().len(), 2);
+  }
+
+  #[test]
+  fn durations_must_be_positive_and_in_range() {
+    let dir = TempDir::new();
+    let mut bans = Bans::empty(dir.0.join("bans"));
+    assert!(bans.add(BanTarget::Account(7), Some(0)).is_err());
+    assert!(bans.add(BanTarget::Account(7), Some(-60)).is_err());
+    assert!(bans.add(BanTarget::Account(7), Some(i64::MAX)).is_err());
+    assert!(!bans.is_id_banned(7));
+    bans.add(BanTarget::Account(7), Some(60)).unwrap();
+    assert!(bans.is_id_banned(7));
+  }
+
+  #[test]
+  fn expired_bans_no_longer_apply() {
+    let now = time::get_time().sec;
+    let bans = Bans {
+      path : PathBuf::new(),
+      bans : vec![
+        Ban { target : network("10.0.0.0/8"), expiry : Some(now - 1) },
+        Ban { target : BanTarget::Account(7), expiry : Some(now - 1) },
+        Ban { target : network("192.168.0.0/16"), expiry : Some(now + 60) },
+        Ban { target : BanTarget::Account(8), expiry : None }
+      ]
+    };
+    assert!(!bans.is_ip_banned(&ip("10.1.2.3")));
+    assert!(!bans.is_id_banned(7));
+    assert!(bans.is_ip_banned(&ip("192.168.3.4")));
+    assert!(bans.is_id_banned(8));
+    assert_eq!(bans.active_bans().len(), 2);
+  }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{self, Debug, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+use crate::model::MessageType;
+use crate::ratelimit::RateLimit;
+use crate::utils::REDACTED;
+
+// message type:burst:tokens per second, for Name, Proxy, LobbyChat, Whisper, WatchDuel, Block, Unblock, Register, Login, AdminLogin and JoinByCode
+const DEFAULT_RATE_LIMITS : &str = "1:3:0.2,5:50:20,9:5:1,11:5:1,14:3:0.1,16:10:1,17:10:1,22:3:0.1,23:3:0.1,27:3:0.1,44:5:0.2";
+
+// what to do when a session claims an identity already used by a live connection
+#[derive(Debug, Clone, PartialEq)]
+pub enum DuplicateSessionPolicy {
+  RejectNew,
+  ReplaceOld
+}
+
+impl FromStr for DuplicateSessionPolicy {
+  type Err = String;
+
+  fn from_str(s : &str) -> Result<DuplicateSessionPolicy, String> {
+    match s {
+      "reject" => Ok(DuplicateSessionPolicy::RejectNew),
+      "replace" => Ok(DuplicateSessionPolicy::ReplaceOld),
+      _ => Err(format!("Unknown duplicate session policy {}", s))
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct Config {
+  pub observer_key        : Option<String>, // required to subscribe to a duel broadcast feed
+  pub broadcast_delay_ms  : u64,
+  pub data_dir            : PathBuf, // where friendships and accounts are persisted
+  pub auth_key            : Option<Vec<u8>>, // hmac sha256 key of the tokens issued by the game backend
+  pub require_auth        : bool,
+  pub duplicate_session   : DuplicateSessionPolicy,
+  pub admins              : Vec<(String, String)>, // name, password
+  pub rate_limits         : HashMap<MessageType::Value, RateLimit>,
+  pub max_rate_violations : f64, // throttled messages tolerated before disconnecting, forgiven at one per second
+  pub max_connections     : usize,
+  pub max_connections_per_ip : usize,
+  pub retry_after_secs    : u64, // hint sent to the clients refused when the server is full
+  pub outbox_high_water   : usize, // queued messages above which list updates are coalesced
+  pub outbox_capacity     : usize, // queued messages above which the player is disconnected
+  pub max_message_length  : usize, // bytes of a message body, longer messages are skipped
+  pub list_update_window_ms : u64 // lobby changes within it make one list update, 0 sends each change
+}
+
+// dumped in the logs, the secrets are left out
+impl Debug for Config {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+    let admin_names : Vec<&String> = self.admins.iter().map(|(name, _)| name).collect();
+    f.debug_struct("Config")
+      .field("observer_key", &self.observer_key.as_ref().map(|_| REDACTED))
+      .field("broadcast_delay_ms", &self.broadcast_delay_ms)
+      .field("data_dir", &self.data_dir)
+      .field("auth_key", &self.auth_key.as_ref().map(|_| REDACTED))
+      .field("require_auth", &self.require_auth)
+      .field("duplicate_session", &self.duplicate_session)
+      .field("admins", &admin_names)
+      .field("rate_limits", &self.rate_limits)
+      .field("max_rate_violations", &self.max_rate_violations)
+      .field("max_connections", &self.max_connections)
+      .field("max_connections_per_ip", &self.max_connections_per_ip)
+      .field("retry_after_secs", &self.retry_after_secs)
+      .field("outbox_high_water", &self.outbox_high_water)
+      .field("outbox_capacity", &self.outbox_capacity)
+      .field("max_message_length", &self.max_message_length)
+      .field("list_update_window_ms", &self.list_update_window_ms)
+      .finish()
+  }
+}
+
+impl Config {
+
+  pub fn from_env() -> Config {
+    Config {
+      observer_key       : env::var("FSERVE_OBSERVER_KEY").ok().filter(|k| !k.is_empty()),
+      broadcast_delay_ms : env_or("FSERVE_BROADCAST_DELAY_SECS", 30) * 1000,
+      data_dir           : PathBuf::from(env_or("FSERVE_DATA_DIR", ".".to_string())),
+      auth_key           : env::var("FSERVE_AUTH_KEY").ok().filter(|k| !k.is_empty()).map(|k| k.into_bytes()),
+      require_auth       : env_or("FSERVE_REQUIRE_AUTH", false),
+      duplicate_session  : env_or("FSERVE_DUPLICATE_SESSION", DuplicateSessionPolicy::RejectNew),
+      admins             : parse_admins(&env::var("FSERVE_ADMINS").unwrap_or_default()),
+      rate_limits        : parse_rate_limits(&env::var("FSERVE_RATE_LIMITS").unwrap_or(DEFAULT_RATE_LIMITS.to_string())),
+      max_rate_violations : env_or("FSERVE_MAX_RATE_VIOLATIONS", 20.0),
+      max_connections    : env_or("FSERVE_MAX_CONNECTIONS", 10000),
+      max_connections_per_ip : env_or("FSERVE_MAX_CONNECTIONS_PER_IP", 20),
+      retry_after_secs   : env_or("FSERVE_RETRY_AFTER_SECS", 30),
+      outbox_high_water  : env_or("FSERVE_OUTBOX_HIGH_WATER", 64),
+      outbox_capacity    : env_or("FSERVE_OUTBOX_CAPACITY", 1024),
+      max_message_length : env_or("FSERVE_MAX_MESSAGE_LENGTH", 1 << 20),
+      list_update_window_ms : env_or("FSERVE_LIST_UPDATE_WINDOW_MS", 200)
+    }
+  }
+}
+
+// name:password,name:password
+fn parse_admins(s : &str) -> Vec<(String, String)> {
+  s.split(',')
+    .filter(|admin| !admin.is_empty())
+    .filter_map(|admin| {
+      match admin.find(':') {
+        Some(i) => Some((admin[..i].to_string(), admin[i+1..].to_string())),
+        None => {
+          warn!("Ignored admin without password {}", admin);
+          None
+        }
+      }
+    })
+    .collect()
+}
+
+// type:burst:rate,type:burst:rate
+fn parse_rate_limits(s : &str) -> HashMap<MessageType::Value, RateLimit> {
+  s.split(',')
+    .filter(|limit| !limit.is_empty())
+    .filter_map(|limit| {
+      let fields : Vec<&str> = limit.split(':').collect();
+      match (fields.len(), fields.first().and_then(|f| f.parse().ok()),
+          fields.get(1).and_then(|f| f.parse().ok()), fields.get(2).and_then(|f| f.parse().ok())) {
+        (3, Some(msg_type), Some(burst), Some(rate)) => Some((msg_type, RateLimit { burst, rate })),
+        _ => {
+          warn!("Ignored rate limit {}", limit);
+          None
+        }
+      }
+    })
+    .collect()
+}
+
+fn env_or<A : FromStr>(name : &str, default : A) -> A {
+  match env::var(name) {
+    Ok(value) => value.parse().unwrap_or_else(|_| {
+      warn!("Invalid value for {} : {}", name, value);
+      default
+    }),
+    Err(_) => default
+  }
+}
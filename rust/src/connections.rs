@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+pub struct Connections {
+  total           : usize,
+  per_ip          : HashMap<IpAddr, usize>,
+  max_total       : usize,
+  max_per_ip      : usize
+}
+
+// released when the connection task ends, however it ends
+pub struct ConnectionGuard {
+  connections : Arc<Mutex<Connections>>,
+  ip          : IpAddr
+}
+
+impl Connections {
+
+  pub fn new(max_total : usize, max_per_ip : usize) -> Connections {
+    Connections {
+      total      : 0,
+      per_ip     : HashMap::new(),
+      max_total,
+      max_per_ip
+    }
+  }
+}
+
+pub fn acquire(connections : &Arc<Mutex<Connections>>, ip : IpAddr) -> Option<ConnectionGuard> {
+  let mut conns = match connections.lock() {
+    Ok(conns) => conns,
+    Err(_) => return None
+  };
+  if conns.total >= conns.max_total {
+    return None
+  }
+  let max_per_ip = conns.max_per_ip;
+  let count = conns.per_ip.entry(ip).or_insert(0);
+  if *count >= max_per_ip {
+    return None
+  }
+  *count += 1;
+  conns.total += 1;
+  Some(ConnectionGuard {
+    connections : connections.clone(),
+    ip
+  })
+}
+
+impl Drop for ConnectionGuard {
+  fn drop(&mut self) {
+    if let Ok(mut conns) = self.connections.lock() {
+      conns.total -= 1;
+      let remaining = match conns.per_ip.get_mut(&self.ip) {
+        Some(count) => {
+          *count -= 1;
+          *count
+        },
+        None => 0
+      };
+      if remaining == 0 {
+        conns.per_ip.remove(&self.ip);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ip(s : &str) -> IpAddr {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn per_ip_slot_is_released_with_its_guard() {
+    let connections = Arc::new(Mutex::new(Connections::new(10, 2)));
+    let first = acquire(&connections, ip("10.0.0.1")).unwrap();
+    let _second = acquire(&connections, ip("10.0.0.1")).unwrap();
+    assert!(acquire(&connections, ip("10.0.0.1")).is_none());
+    assert!(acquire(&connections, ip("10.0.0.2")).is_some());
+    drop(first);
+    assert!(acquire(&connections, ip("10.0.0.1")).is_some());
+  }
+
+  #[test]
+  fn total_slot_is_released_with_its_guard() {
+    let connections = Arc::new(Mutex::new(Connections::new(2, 2)));
+    let first = acquire(&connections, ip("10.0.0.1")).unwrap();
+    let _second = acquire(&connections, ip("10.0.0.3")).unwrap();
+    assert!(acquire(&connections, ip("10.0.0.2")).is_none());
+    drop(first);
+    let _third = acquire(&connections, ip("10.0.0.2")).unwrap();
+    let conns = connections.lock().unwrap();
+    assert_eq!(conns.total, 2);
+    assert!(!conns.per_ip.contains_key(&ip("10.0.0.1")));
+  }
+}
@@ -1,82 +1,462 @@
 
 use std::sync::Arc;
-use rand::{thread_rng, sample};
-use model::*;
-use state::*;
-use utils::*;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use base64::{encode, decode};
+use crate::accounts::{Credentials, LOCAL_ID_FLAG};
+use crate::auth::verify_token;
+use crate::bans::BanTarget;
+use crate::config::DuplicateSessionPolicy;
+use crate::friends::*;
+use crate::model::*;
+use crate::state::*;
+use crate::utils::*;
+
+const MAX_CHAT_LENGTH : usize = 256;
+// the blocked ids are copied at each list broadcast
+const MAX_BLOCKED_PLAYERS : usize = 200;
+const MAX_ADMIN_LOGIN_FAILURES : u32 = 3;
+const MAX_OBSERVER_KEY_FAILURES : u32 = 3;
+const PING_INTERVAL_MS : u64 = 10000;
+// an echo arriving later than this is not a latency worth showing
+const MAX_RTT_MS : u64 = 3 * PING_INTERVAL_MS;
+
+pub type HandlerParam = (HandlerMessage, Arc<Player>);
 
 pub enum HandlerMessage {
   ClientMessage(Arc<Message>),
   AddPlayer,
-  ReleasePlayer
+  ReleasePlayer,
+  PasswordChecked(Arc<Message>, PasswordCheck) // a Register or Login request once its password is hashed
+}
+
+// the outcome of hashing a password off the handler thread
+pub enum PasswordCheck {
+  Register(String, Credentials),
+  Login(String, bool)
 }
 
 pub fn handle_msg(handler_msg : HandlerMessage, player : Arc<Player>, server_state : &mut State) -> BasicResult<()> {
   match handler_msg {
-    HandlerMessage::AddPlayer => server_state.players.push(player),
-    HandlerMessage::ReleasePlayer => try!(release_player(&player, server_state)),
+    HandlerMessage::AddPlayer => server_state.players.add(player),
+    _ if player.is_closed() => debug!("Ignored msg from closed session {}", player.id()),
+    HandlerMessage::ReleasePlayer => release_player(&player, server_state)?,
+    HandlerMessage::PasswordChecked(msg, check) => password_checked(player, msg, check, server_state)?,
     HandlerMessage::ClientMessage(msg) => {
-      debug!("msg type {} -> {}", msg.header.message_type, player.id);
+      debug!("msg type {} -> {}", msg.header.message_type, player.id());
+      // admins have no backend identity
+      if server_state.config.require_auth && !player.is_authenticated()?
+        && !matches!(msg.header.message_type, MessageType::Auth | MessageType::Ping | MessageType::AdminLogin) {
+        return answer_error("Authentication required", &player, msg.clone())
+      }
+      if MessageType::is_privileged(msg.header.message_type) && !player.is_admin()? {
+        return answer_error("Not authorised", &player, msg.clone())
+      }
+      if MessageType::needs_account(msg.header.message_type) && !player.is_authenticated()? {
+        return answer_error("Friends need an account", &player, msg.clone())
+      }
       match msg.header.message_type {
         MessageType::RequestDuel => {
-          if try!(player.is_on_hold()) {
-            let req_id : Id = try!(try!(msg.body_as_str()).parse()); // FIXME
-            match find_player_on_hold(req_id, server_state) {
-              Some(other_player) => {
-                if server_state.has_request(req_id) {
-                  let duel = Duel {
-                    player1 : player.clone(),
-                    player2 : other_player.clone()
-                  };
-                  // !! this would not be safe if it happens on different threads
-                  try!(player.set_status(PlayerStatus::Duelling(duel.clone())));
-                  try!(other_player.set_status(PlayerStatus::Duelling(duel)));
-                  server_state.purge_request(player.id);
-                  server_state.purge_request(other_player.id);
-                  let mut rng = thread_rng();
-                  let master = sample(&mut rng, vec![player, other_player], 1).pop().unwrap();
-                  try!(send(Arc::new(Message::new(MessageType::NewGame, "")), &master));
-                  try!(broadcast_list_to_onhold(&server_state));
-                } else {
-                  server_state.add_request(Request{src_id : player.id, dest_id : other_player.id});
-                  try!(send(Arc::new(Message::new(MessageType::RequestDuel, &player.id.to_string())), &other_player));
-                }
-              },
-              None => {
-                warn!("Not found player requested {}", req_id);
-                try!(send(Arc::new(Message::new(MessageType::RequestFailed, "")), &player));
-              }
-            }
-          } else {
-            warn!("Already in duel {}", player.id)
+          let req_id : Id = msg.body_as_str()?.parse()?; // FIXME
+          request_duel(player, req_id, server_state)?
+        },
+        MessageType::RequestDuelByName => {
+          match find_by_name_for(&player, msg.body_as_str()?, server_state) {
+            Ok(other_player) => request_duel(player, other_player.id(), server_state)?,
+            Err(err) => answer_error(&err, &player, msg.clone())?
+          }
+        },
+        MessageType::CreatePrivateDuel => {
+          if !player.is_on_hold()? {
+            return answer_error("Cannot create a private duel while duelling", &player, msg.clone())
+          }
+          // hidden flag, and the base64 password if any
+          let body = msg.body_as_str()?;
+          let (hidden, password) = match body.find(':') {
+            Some(i) => (&body[..i] == "1", Some(String::from_utf8(decode(&body[i+1..])?)?).filter(|p| !p.is_empty())),
+            None => (body == "1", None)
+          };
+          let code = server_state.open_room(PrivateRoom { creator : player.id(), password, hidden });
+          info!("Player {} created private duel {}", player.id(), code);
+          answer(Message::new(MessageType::CreatePrivateDuel, &code), &player, msg.clone())?;
+          update_list(server_state, &player.lobby()?)
+        },
+        MessageType::JoinByCode => join_by_code(player, msg, server_state)?,
+        MessageType::FindPlayer => {
+          match find_by_name_for(&player, msg.body_as_str()?, server_state) {
+            Ok(other_player) => {
+              let found = player_string(&other_player)?.unwrap_or_default();
+              answer(Message::new(MessageType::FindPlayer, &found), &player, msg.clone())?
+            },
+            Err(err) => answer_error(&err, &player, msg.clone())?
           }
         },
         MessageType::Proxy => {
-          let state = try!(box_err(player.state.read()));
-          match state.status {
-            PlayerStatus::Duelling(ref duel) => try!(send_to_other(msg, duel, player.id)),
-            PlayerStatus::OnHold => {
-              let to_purge = try!(broadcast(msg, &server_state.players));
+          match server_state.duel_of(player.id()) {
+            Some(duel) => {
+              send_to_other(msg.clone(), &duel, player.id(), server_state)?;
+              feed_observers(&msg, &duel, player.id(), server_state)
+            },
+            None => {
+              // out of a duel, only the lobby gets it, as for LobbyChat
+              let listeners : Vec<Arc<Player>> = players_on_hold(server_state, &player.lobby()?).into_iter()
+                .filter(|p| !p.has_blocked_unsafe(player.id()))
+                .collect();
+              let to_purge = broadcast(msg, &listeners)?;
               check_purge(server_state, to_purge)
             }
           }
         },
+        MessageType::LobbyChat => {
+          if msg.body.len() > MAX_CHAT_LENGTH {
+            answer_error(&format!("Chat message longer than {}", MAX_CHAT_LENGTH), &player, msg)?
+          } else if !player.is_on_hold()? {
+            answer_error("Lobby chat is only available on hold", &player, msg)?
+          } else {
+            let text = msg.body_as_str()?;
+            let chat = sender_prefixed(&player, text)?;
+            let listeners : Vec<Arc<Player>> = players_on_hold(server_state, &player.lobby()?).into_iter()
+              .filter(|p| !p.has_blocked_unsafe(player.id()))
+              .collect();
+            let to_purge = broadcast(Arc::new(Message::new(MessageType::LobbyChat, &chat)), &listeners)?;
+            check_purge(server_state, to_purge)
+          }
+        },
+        MessageType::Whisper => {
+          // dest id:text
+          let body = msg.body_as_str()?;
+          let parsed = body.find(':').and_then(|i| body[..i].parse().ok().map(|dest_id : Id| (dest_id, &body[i+1..])));
+          let (dest_id, text) = match parsed {
+            Some(parsed) => parsed,
+            None => return answer_error("Whisper expects dest id:text", &player, msg.clone())
+          };
+          if text.len() > MAX_CHAT_LENGTH {
+            answer_error(&format!("Chat message longer than {}", MAX_CHAT_LENGTH), &player, msg.clone())?
+          } else {
+            match find_player(dest_id, server_state) {
+              Some(ref dest) if dest.has_blocked_unsafe(player.id()) => {
+                debug!("Ignored whisper {} -> {}, blocked", player.id(), dest_id)
+              },
+              Some(dest) => {
+                let whisper = sender_prefixed(&player, text)?;
+                send(Arc::new(Message::new(MessageType::Whisper, &whisper)), &dest)?
+              },
+              None => answer_error(&format!("Unknown player {}", dest_id), &player, msg.clone())?
+            }
+          }
+        },
         MessageType::Name => {
-          let body = try!(msg.body_as_str());
-          try!(player.set_name(body.to_string()));
-          info!("Set name {} to {}", &body, player.id);
-          let to_purge = try!(broadcast_list_to_onhold(&server_state));
-          check_purge(server_state, to_purge);
+          let body = msg.body_as_str()?;
+          let old_name = player.name()?;
+          if player.is_authenticated()? {
+            return answer_error("Name is set by the account", &player, msg.clone())
+          } else if server_state.accounts.exists(body) {
+            return answer_error(&format!("Name {} is registered", body), &player, msg.clone())
+          } else if name_in_use(body, &player, server_state) {
+            return answer_error(&format!("Name {} is already in use", body), &player, msg.clone())
+          }
+          if !old_name.is_empty() && old_name != body {
+            notify_friends(&player, PRESENCE_OFFLINE, server_state);
+          }
+          server_state.players.set_name(&player, body.to_string())?;
+          info!("Set name {} to {}", &body, player.id());
+          if old_name != body {
+            notify_friends(&player, presence(&player)?, server_state);
+          }
+          update_list(server_state, &player.lobby()?);
         },
         MessageType::ListPlayers => {
-          let player_list = try!(server_state.player_list_string());
-          try!(answer(Message::new(MessageType::ListPlayers, &player_list), &player, msg))
+          let body = msg.body_as_str()?;
+          if body.is_empty() {
+            let player_list = server_state.player_list_string(&player.lobby()?, &player.blocked()?)?;
+            answer(Message::new(MessageType::ListPlayers, &player_list), &player, msg)?
+          } else {
+            match ListQuery::parse(body) {
+              Ok(query) => {
+                let lobby = match query.lobby {
+                  Some(ref lobby) => lobby.clone(),
+                  None => player.lobby()?
+                };
+                let player_list = server_state.query_player_list_string(&lobby, &query, &player.blocked()?)?;
+                answer(Message::new(MessageType::ListPlayers, &player_list), &player, msg.clone())?
+              },
+              Err(err) => answer_error(&format!("Invalid list query : {}", err), &player, msg.clone())?
+            }
+          }
+        },
+        MessageType::ListDeltas => {
+          if msg.body_as_str()? == "0" {
+            player.set_list_version(None)?
+          } else {
+            player.set_list_version(Some(UNKNOWN_LIST_VERSION))?;
+            answer_snapshot(&player, msg, server_state)?
+          }
+        },
+        MessageType::ListSnapshot => answer_snapshot(&player, msg, server_state)?,
+        MessageType::JoinLobby => {
+          let lobby = msg.body_as_str()?.to_string();
+          let old_lobby = player.lobby()?;
+          if lobby.is_empty() || lobby.len() > MAX_LOBBY_NAME_LENGTH {
+            answer_error(&format!("Lobby name must have 1 to {} bytes", MAX_LOBBY_NAME_LENGTH), &player, msg)?
+          } else if !player.is_on_hold()? {
+            answer_error("Cannot change lobby while duelling", &player, msg)?
+          } else if lobby != old_lobby {
+            player.set_lobby(lobby.clone())?;
+            server_state.purge_request(player.id());
+            info!("Player {} moved from lobby {} to {}", player.id(), old_lobby, lobby);
+            update_list(server_state, &old_lobby);
+            update_list(server_state, &lobby)
+          }
+        },
+        MessageType::Block | MessageType::Unblock => {
+          let blocked_id : Id = msg.body_as_str()?.parse()?;
+          let block = msg.header.message_type == MessageType::Block;
+          if block && !player.has_blocked_unsafe(blocked_id) && player.blocked_count()? >= MAX_BLOCKED_PLAYERS {
+            return answer_error(&format!("Cannot block more than {} players", MAX_BLOCKED_PLAYERS), &player, msg.clone())
+          }
+          player.set_blocked(blocked_id, block)?;
+          if block {
+            server_state.purge_request_between(blocked_id, player.id());
+          }
+          info!("Player {} {} {}", player.id(), if block { "blocked" } else { "unblocked" }, blocked_id);
+        },
+        MessageType::FriendRequest => {
+          let name = player.name()?;
+          let friend_name = msg.body_as_str()?;
+          if name.is_empty() || friend_name.is_empty() || name == friend_name {
+            answer_error("Friend request needs a name and a different friend name", &player, msg.clone())?
+          } else {
+            match server_state.friends.request(&name, friend_name)? {
+              FriendRequestOutcome::Requested => {
+                let request = Arc::new(Message::new(MessageType::FriendRequest, &encode(name.as_bytes())));
+                for friend in find_members_by_name(friend_name, server_state) {
+                  if !friend.has_blocked_unsafe(player.id()) {
+                    send(request.clone(), &friend)?;
+                  }
+                }
+              },
+              FriendRequestOutcome::Accepted => {
+                info!("Friendship {} <-> {}", name, friend_name);
+                notify_friends(&player, presence(&player)?, server_state);
+                let friend_presence = friend_presence(friend_name, server_state);
+                send(Arc::new(friend_status_message(friend_name, friend_presence)), &player)?;
+              },
+              FriendRequestOutcome::AlreadyFriends => ()
+            }
+          }
+        },
+        MessageType::RemoveFriend => {
+          let name = player.name()?;
+          let friend_name = msg.body_as_str()?;
+          if server_state.friends.remove(&name, friend_name)? {
+            info!("Friendship removed {} <-> {}", name, friend_name);
+          }
+        },
+        MessageType::ListFriends => {
+          let name = player.name()?;
+          let friend_strings : Vec<String> = server_state.friends.friends_of(&name).iter()
+            .map(|friend_name| {
+              format!("{}:{}", encode(friend_name.as_bytes()), friend_presence(friend_name, server_state))
+            })
+            .collect();
+          answer(Message::new(MessageType::ListFriends, &friend_strings.join(";")), &player, msg)?
+        },
+        MessageType::Register | MessageType::Login => {
+          // base64 name:password
+          let body = msg.body_as_str()?;
+          let (name, password) = match body.find(':') {
+            Some(i) => (String::from_utf8(decode(&body[..i])?)?, &body[i+1..]),
+            None => return answer_error("Expected base64 name:password", &player, msg.clone())
+          };
+          if !player.is_on_hold()? {
+            answer_error("Cannot log in while duelling", &player, msg.clone())?
+          } else if name.is_empty() || password.is_empty() {
+            answer_error("Name and password cannot be empty", &player, msg.clone())?
+          } else if msg.header.message_type == MessageType::Register && server_state.accounts.exists(&name) {
+            answer_error(&format!("Account {} already exists", name), &player, msg.clone())?
+          } else if msg.header.message_type == MessageType::Register && name_in_use(&name, &player, server_state) {
+            answer_error(&format!("Name {} is already in use", name), &player, msg.clone())?
+          } else {
+            let credentials = server_state.accounts.credentials(&name);
+            if msg.header.message_type == MessageType::Login && credentials.is_none() {
+              answer_error("Invalid name or password", &player, msg.clone())?
+            } else {
+              check_password(player, msg.clone(), name, password.to_string(), credentials, server_state)
+            }
+          }
+        },
+        MessageType::Auth => {
+          let token_result = match server_state.config.auth_key {
+            Some(ref key) => verify_token(msg.body_as_str()?, key),
+            None => Err(From::from("Authentication tokens are not configured"))
+          };
+          match token_result {
+            Ok(token) => {
+              // backend users and local accounts never share an id or a name
+              if !player.is_on_hold()? {
+                answer_error("Cannot authenticate while duelling", &player, msg.clone())?
+              } else if token.user_id & LOCAL_ID_FLAG != 0 {
+                answer_error(&format!("User id {} is reserved for local accounts", token.user_id), &player, msg.clone())?
+              } else if server_state.accounts.exists(&token.name) {
+                answer_error(&format!("Name {} is a local account", token.name), &player, msg.clone())?
+              } else {
+                match claim_identity(&player, token.user_id, token.name.clone(), server_state)? {
+                  Claim::Claimed => {
+                    let identity = format!("{}:{}", encode(token.name.as_bytes()), token.user_id);
+                    answer(Message::new(MessageType::Auth, &identity), &player, msg.clone())?
+                  },
+                  Claim::AlreadyConnected => answer_error(&format!("User {} is already connected", token.user_id), &player, msg.clone())?,
+                  Claim::NameInUse => answer_error(&format!("Name {} is already in use", token.name), &player, msg.clone())?
+                }
+              }
+            },
+            Err(err) => answer_error(&format!("Invalid token : {}", err), &player, msg.clone())?
+          }
+        },
+        MessageType::ListLobbies => {
+          let lobby_list = server_state.lobby_list_string()?;
+          answer(Message::new(MessageType::ListLobbies, &lobby_list), &player, msg)?
+        },
+        MessageType::WatchDuel => {
+          // observer key:player id
+          let body = msg.body_as_str()?;
+          let (key, player_str) = match body.rfind(':') {
+            Some(i) => (&body[..i], &body[i+1..]),
+            None => return answer_error("WatchDuel expects key:player id", &player, msg.clone())
+          };
+          let watched_id : Id = player_str.parse()?;
+          if !server_state.config.observer_key.as_ref().map(|k| constant_time_eq(k.as_bytes(), key.as_bytes())).unwrap_or(false) {
+            warn!("Wrong observer key from {}", player.id());
+            if player.fail_observer_key()? >= MAX_OBSERVER_KEY_FAILURES {
+              disconnect(&player, "Too many wrong observer keys", server_state)?
+            } else {
+              answer_error("Not authorised to watch duels", &player, msg.clone())?
+            }
+          } else {
+            let duelling = match find_player(watched_id, server_state) {
+              Some(watched) => !watched.is_on_hold()?,
+              None => false
+            };
+            if duelling {
+              server_state.subscribe(Subscription { observer_id : player.id(), player_id : watched_id });
+              info!("Observer {} watching duel of {}", player.id(), watched_id);
+            } else {
+              answer_error(&format!("Player {} is not duelling", watched_id), &player, msg.clone())?
+            }
+          }
         },
         MessageType::ExitDuel => {
-          try!(exit_duel(&player));
-          let to_purge = try!(broadcast_list_to_onhold(server_state));
+          exit_duel(&player, server_state)?;
+          update_list(server_state, &player.lobby()?)
+        },
+        MessageType::Ping => {
+          // the client echoes back the timestamp we sent in ping_players, only the last one is measured
+          let sent : u64 = msg.body_as_str()?.parse()?;
+          let sample = now_ms().saturating_sub(sent);
+          if sent != server_state.last_ping || sample > MAX_RTT_MS {
+            debug!("Ignored ping {} from {}", sent, player.id())
+          } else {
+            player.update_rtt(sample as u32)?
+          }
+        },
+        MessageType::AdminLogin => {
+          // base64 name:password
+          let body = msg.body_as_str()?;
+          let (name, password) = match body.find(':') {
+            Some(i) => (String::from_utf8(decode(&body[..i])?)?, &body[i+1..]),
+            None => return answer_error("Expected base64 name:password", &player, msg.clone())
+          };
+          let valid = server_state.config.admins.iter()
+            .any(|(admin, admin_password)| {
+              *admin == name && constant_time_eq(admin_password.as_bytes(), password.as_bytes())
+            });
+          if valid {
+            player.set_admin()?;
+            info!("Player {} is admin {}", player.id(), name);
+            answer(Message::new(MessageType::AdminLogin, ""), &player, msg.clone())?
+          } else {
+            warn!("Failed admin login of {} as {}", player.id(), name);
+            if player.fail_admin_login()? >= MAX_ADMIN_LOGIN_FAILURES {
+              disconnect(&player, "Too many failed admin logins", server_state)?
+            } else {
+              answer_error("Invalid admin name or password", &player, msg.clone())?
+            }
+          }
+        },
+        MessageType::Kick => {
+          let target_id : Id = msg.body_as_str()?.parse()?;
+          match find_player(target_id, server_state) {
+            Some(target) => {
+              info!("Admin {} kicked {}", player.id(), target_id);
+              disconnect(&target, "Kicked by an admin", server_state)?
+            },
+            None => answer_error(&format!("Unknown player {}", target_id), &player, msg.clone())?
+          }
+        },
+        MessageType::Ban => {
+          // id or ip[/prefix], optionally followed by ;duration in seconds
+          let body = msg.body_as_str()?;
+          let (target_str, duration) = match body.find(';') {
+            Some(i) => match body[i+1..].parse::<i64>() {
+              Ok(duration) if duration > 0 => (&body[..i], Some(duration)),
+              _ => return answer_error("Ban duration must be a positive number of seconds", &player, msg.clone())
+            },
+            None => (body, None)
+          };
+          let target = BanTarget::parse(target_str)?;
+          if let Err(err) = box_err(server_state.bans.lock())?.add(target.clone(), duration) {
+            return answer_error(&format!("Failed banning {} : {}", target, err), &player, msg.clone())
+          }
+          info!("Admin {} banned {} for {:?}s", player.id(), target, duration);
+          let banned : Vec<Arc<Player>> = server_state.players.iter()
+            .filter(|p| {
+              match target {
+                BanTarget::Account(id) => p.id() == id,
+                BanTarget::Network(..) => p.addr.as_ref().map(|ip| target.contains(ip)).unwrap_or(false)
+              }
+            }).cloned()
+            .collect();
+          for banned_player in banned {
+            disconnect(&banned_player, "Banned", server_state)?;
+          }
+        },
+        MessageType::Unban => {
+          let target = BanTarget::parse(msg.body_as_str()?)?;
+          if box_err(server_state.bans.lock())?.remove(&target)? {
+            info!("Admin {} unbanned {}", player.id(), target);
+          } else {
+            answer_error(&format!("{} is not banned", target), &player, msg.clone())?
+          }
+        },
+        MessageType::ListBans => {
+          // one target;expiry per line
+          let ban_strings : Vec<String> = box_err(server_state.bans.lock())?.active_bans().iter()
+            .map(|ban| format!("{};{}", ban.target, ban.expiry.unwrap_or(0)))
+            .collect();
+          answer(Message::new(MessageType::ListBans, &ban_strings.join("\n")), &player, msg.clone())?
+        },
+        MessageType::Announce => {
+          let text = msg.body_as_str()?;
+          info!("Admin {} announced {}", player.id(), text);
+          let to_purge = broadcast(Arc::new(Message::new(MessageType::Announce, text)), server_state.players.iter())?;
           check_purge(server_state, to_purge)
         },
+        MessageType::EndDuel => {
+          let target_id : Id = msg.body_as_str()?.parse()?;
+          match find_player(target_id, server_state) {
+            Some(ref target) if !target.is_on_hold()? => {
+              let other_player = find_duel_other_player(target, server_state);
+              exit_duel(target, server_state)?;
+              send(Arc::new(Message::new(MessageType::ExitDuel, "")), target)?;
+              info!("Admin {} ended duel of {}", player.id(), target_id);
+              update_list(server_state, &target.lobby()?);
+              if let Some(other_player) = other_player {
+                update_list(server_state, &other_player.lobby()?)
+              }
+            },
+            _ => answer_error(&format!("Player {} is not duelling", target_id), &player, msg.clone())?
+          }
+        },
         MessageType::Dump => info!("Dump :\n{:?}", server_state),
         _ => return Err(From::from(format!("Not managed msg type {}", msg.header.message_type)))
       }
@@ -85,103 +465,1076 @@ pub fn handle_msg(handler_msg : HandlerMessage, player : Arc<Player>, server_sta
   Ok(())
 }
 
-fn exit_duel(player : &Player) -> BasicResult<()> {
-  if let Some(other_player) = try!(find_duel_other_player(player)) {
-    try!(player.set_status(PlayerStatus::OnHold));
-    try!(other_player.set_status(PlayerStatus::OnHold));
-    try!(send(Arc::new(Message::new(MessageType::ExitDuel, "")), &other_player));
-    info!("Exit duel {} -> {}", player.id, other_player.id);
+// pbkdf2 takes a while, so it runs on a blocking task and its outcome comes back to the handler
+fn check_password(player : Arc<Player>, msg : Arc<Message>, name : String, password : String,
+    credentials : Option<Credentials>, server_state : &State) {
+  let handler_tx = server_state.handler_tx.clone();
+  tokio::task::spawn_blocking(move || {
+    let check = match credentials {
+      Some(credentials) => PasswordCheck::Login(name, credentials.matches(&password)),
+      None => PasswordCheck::Register(name, Credentials::new(&password))
+    };
+    if handler_tx.send((HandlerMessage::PasswordChecked(msg, check), player)).is_err() {
+      error!("Handler disconnected before a password check ended");
+    }
+  });
+}
+
+fn password_checked(player : Arc<Player>, msg : Arc<Message>, check : PasswordCheck, server_state : &mut State) -> BasicResult<()> {
+  if !server_state.players.contains(&player) {
+    return Ok(()) // released meanwhile
+  } else if !player.is_on_hold()? {
+    return answer_error("Cannot log in while duelling", &player, msg)
   }
-  Ok(())
+  let account = match check {
+    PasswordCheck::Register(name, credentials) => {
+      // checked again as it may have changed while hashing, nothing is saved if the identity cannot be claimed
+      if server_state.accounts.exists(&name) {
+        return answer_error(&format!("Account {} already exists", name), &player, msg)
+      } else if name_in_use(&name, &player, server_state) {
+        return answer_error(&format!("Name {} is already in use", name), &player, msg)
+      }
+      server_state.accounts.register(&name, credentials)?
+    },
+    PasswordCheck::Login(name, true) => match server_state.accounts.get(&name) {
+      Some(account) => account.clone(),
+      None => return answer_error("Invalid name or password", &player, msg)
+    },
+    PasswordCheck::Login(_, false) => return answer_error("Invalid name or password", &player, msg)
+  };
+  match claim_identity(&player, account.id, account.name.clone(), server_state)? {
+    Claim::Claimed => answer(Message::new(MessageType::Login, &account.to_string()), &player, msg),
+    Claim::AlreadyConnected => answer_error(&format!("Account {} is already connected", account.name), &player, msg),
+    Claim::NameInUse => answer_error(&format!("Name {} is already in use", account.name), &player, msg)
+  }
+}
+
+fn name_in_use(name : &str, player : &Player, server_state : &State) -> bool {
+  find_players_by_name(name, server_state).iter().any(|p| p.id() != player.id())
+}
+
+// the player is released right away, its connection is closed once the notice is written
+fn disconnect(player : &Arc<Player>, reason : &str, server_state : &mut State) -> BasicResult<()> {
+  release_player(player, server_state)?;
+  player.close();
+  send(Arc::new(Message::new(MessageType::Disconnected, reason)), player)
 }
 
-fn find_duel_other_player(player : &Player) -> BasicResult<Option<Arc<Player>>> {
-  let player_state = try!(box_err(player.state.read()));
-  if let PlayerStatus::Duelling(ref duel) = player_state.status {
-    Ok(Some(duel.other_player(player.id)))
+enum Claim {
+  Claimed,
+  AlreadyConnected, // the identity has another live session and the policy is to reject the new one
+  NameInUse // an authenticated session of another identity holds the name, it is never replaced
+}
+
+fn claim_identity(player : &Arc<Player>, id : Id, name : String, server_state : &mut State) -> BasicResult<Claim> {
+  if box_err(server_state.bans.lock())?.is_id_banned(id) {
+    disconnect(player, "Banned", server_state)?;
+    return Err(From::from(format!("Banned {} tried to log in", id)))
+  }
+  let holders : Vec<Arc<Player>> = find_players_by_name(&name, server_state).into_iter()
+    .filter(|p| p.id() != id && p.id() != player.id())
+    .collect();
+  for holder in holders.iter() {
+    if holder.is_authenticated()? {
+      return Ok(Claim::NameInUse)
+    }
+  }
+  let sessions : Vec<Arc<Player>> = server_state.players.get(id).into_iter()
+    .filter(|p| !Arc::ptr_eq(p, player)).cloned()
+    .collect();
+  if !sessions.is_empty() && server_state.config.duplicate_session == DuplicateSessionPolicy::RejectNew {
+    return Ok(Claim::AlreadyConnected)
+  }
+  // the verified identity wins over a guest holding its name
+  for guest in holders.iter() {
+    info!("Guest {} disconnected, its name {} was claimed by {}", guest.id(), name, id);
+    disconnect(guest, "Name claimed by its account", server_state)?;
+  }
+  if sessions.is_empty() {
+    login(player, id, name, server_state)?;
+    Ok(Claim::Claimed)
+  } else {
+    let mut duel_opponent = None;
+    for old_player in sessions.iter() {
+      if let Some(other_player) = replace_session(old_player, server_state)? {
+        duel_opponent = Some(other_player);
+      }
+    }
+    // the duel of the old session is kept in the duel table under the account id, the new session takes it over on login
+    if duel_opponent.is_some() {
+      server_state.players.set_status(player, PlayerStatus::Duelling)?;
+    }
+    login(player, id, name, server_state)?;
+    if let Some(other_player) = duel_opponent {
+      info!("Duel of {} transferred against {}", player.id(), other_player.id());
+      send(Arc::new(Message::new(MessageType::ResumeDuel, &other_player.id().to_string())), player)?;
+    }
+    Ok(Claim::Claimed)
+  }
+}
+
+// disconnects the old session without ending its duel, returns the duel opponent
+fn replace_session(old_player : &Arc<Player>, server_state : &mut State) -> BasicResult<Option<Arc<Player>>> {
+  let other_player = find_duel_other_player(old_player, server_state);
+  server_state.players.remove(old_player);
+  server_state.purge_request(old_player.id());
+  server_state.close_room(old_player.id());
+  old_player.set_status(PlayerStatus::OnHold)?;
+  old_player.close();
+  if let Err(err) = send(Arc::new(Message::new(MessageType::SessionReplaced, "")), old_player) {
+    warn!("Failed notifying replaced session {} : {}", old_player.id(), err);
+  }
+  info!("Session of {} replaced", old_player.id());
+  Ok(other_player)
+}
+
+// the duel starts when the requested player had requested it too
+fn request_duel(player : Arc<Player>, req_id : Id, server_state : &mut State) -> BasicResult<()> {
+  if player.is_on_hold()? {
+    let lobby = player.lobby()?;
+    match find_player_on_hold(req_id, server_state).into_iter().find(|p| p.is_in_lobby_unsafe(&lobby)) {
+      Some(ref other_player) if other_player.has_blocked_unsafe(player.id()) => {
+        debug!("Ignored duel request {} -> {}, blocked", player.id(), req_id)
+      },
+      Some(other_player) => {
+        if server_state.has_request(req_id, player.id()) {
+          begin_duel(player, other_player, server_state)?;
+        } else {
+          server_state.add_request(player.id(), other_player.id());
+          send(Arc::new(Message::new(MessageType::RequestDuel, &player.id().to_string())), &other_player)?;
+        }
+      },
+      None => {
+        warn!("Not found player requested {}", req_id);
+        send(Arc::new(Message::new(MessageType::RequestFailed, "")), &player)?;
+      }
+    }
   } else {
-    Ok(None)
+    warn!("Already in duel {}", player.id())
+  }
+  Ok(())
+}
+
+fn begin_duel(player : Arc<Player>, other_player : Arc<Player>, server_state : &mut State) -> BasicResult<()> {
+  server_state.start_duel(player.id(), other_player.id());
+  // !! this would not be safe if it happens on different threads
+  server_state.players.set_status(&player, PlayerStatus::Duelling)?;
+  server_state.players.set_status(&other_player, PlayerStatus::Duelling)?;
+  for p in [&player, &other_player].iter() {
+    server_state.purge_request(p.id());
+    server_state.close_room(p.id());
+    notify_friends(p, PRESENCE_DUELLING, server_state);
+    if p.is_authenticated()? {
+      server_state.accounts.add_duel(p.id());
+    }
+  }
+  let lobbies = [player.lobby()?, other_player.lobby()?];
+  let mut rng = thread_rng();
+  let master = [player, other_player].choose(&mut rng).unwrap().clone();
+  send(Arc::new(Message::new(MessageType::NewGame, "")), &master)?;
+  update_list(server_state, &lobbies[0]);
+  if lobbies[1] != lobbies[0] {
+    update_list(server_state, &lobbies[1]);
   }
+  Ok(())
 }
 
-fn send_to_other(msg : Arc<Message>, duel : &Duel, current : Id) -> BasicResult<()> {
-  let other_player = duel.other_player(current);
-  let tx = try!(box_err(other_player.tx.lock()));
-  tx.send(msg).map_err(From::from)
+// the holder of the code joins the creator wherever its lobby, if it knows the password
+fn join_by_code(player : Arc<Player>, msg : Arc<Message>, server_state : &mut State) -> BasicResult<()> {
+  let body = msg.body_as_str()?;
+  let (code, given_password) = match body.find(':') {
+    Some(i) => (body[..i].to_uppercase(), Some(String::from_utf8(decode(&body[i+1..])?)?)),
+    None => (body.to_uppercase(), None)
+  };
+  // the same answer for an unknown code and a wrong password, so that codes cannot be probed
+  let creator_id = match server_state.room(&code) {
+    Some(room) if room_password_matches(&room.password, &given_password) => room.creator,
+    _ => return answer_error("Invalid duel code or password", &player, msg.clone())
+  };
+  if creator_id == player.id() {
+    return answer_error("Cannot join your own duel", &player, msg.clone())
+  } else if !player.is_on_hold()? {
+    return answer_error("Already duelling", &player, msg.clone())
+  }
+  match find_player_on_hold(creator_id, server_state) {
+    Some(ref creator) if creator.has_blocked_unsafe(player.id()) => answer_error("Duel unavailable", &player, msg.clone()),
+    Some(creator) => {
+      info!("Player {} joined the private duel of {}", player.id(), creator_id);
+      begin_duel(player, creator, server_state)
+    },
+    None => answer_error("Duel unavailable", &player, msg.clone())
+  }
+}
+
+fn room_password_matches(password : &Option<String>, given : &Option<String>) -> bool {
+  match (password, given) {
+    (None, _) => true,
+    (Some(password), Some(given)) => constant_time_eq(password.as_bytes(), given.as_bytes()),
+    (Some(_), None) => false
+  }
+}
+
+// players blocking the requester are not found
+fn find_by_name_for(player : &Player, name : &str, server_state : &State) -> Result<Arc<Player>, String> {
+  server_state.players.named(name)
+    .filter(|p| p.id() != player.id() && !p.has_blocked_unsafe(player.id())).cloned()
+    .ok_or_else(|| format!("No player named {}", name))
+}
+
+// the player takes the identity of the account, anything referencing the connection id is dropped
+fn login(player : &Player, id : Id, name : String, server_state : &mut State) -> BasicResult<()> {
+  let old_id = player.id();
+  let old_name = player.name()?;
+  server_state.purge_request(old_id);
+  server_state.close_room(old_id);
+  server_state.purge_subscription(old_id);
+  if !old_name.is_empty() && old_name != name {
+    notify_friends(player, PRESENCE_OFFLINE, server_state);
+  }
+  server_state.players.set_id(player, id);
+  server_state.rename_duellist(old_id, id);
+  server_state.players.authenticate(player, name.clone())?;
+  info!("Player {} logged in as {} ({})", old_id, name, id);
+  notify_friends(player, presence(player)?, server_state);
+  update_list(server_state, &player.lobby()?);
+  Ok(())
+}
+
+fn exit_duel(player : &Player, server_state : &mut State) -> BasicResult<()> {
+  if let Some(duel) = server_state.end_duel(player.id()) {
+    let other_id = duel.other_player(player.id());
+    server_state.end_feed(player.id());
+    server_state.end_feed(other_id);
+    server_state.players.set_status(player, PlayerStatus::OnHold)?;
+    notify_friends(player, PRESENCE_ON_HOLD, server_state);
+    if let Some(other_player) = find_player(other_id, server_state) {
+      server_state.players.set_status(&other_player, PlayerStatus::OnHold)?;
+      notify_friends(&other_player, PRESENCE_ON_HOLD, server_state);
+      send(Arc::new(Message::new(MessageType::ExitDuel, "")), &other_player)?;
+    }
+    info!("Exit duel {} -> {}", player.id(), other_id);
+  }
+  Ok(())
+}
+
+fn find_duel_other_player(player : &Player, server_state : &State) -> Option<Arc<Player>> {
+  server_state.duel_of(player.id())
+    .and_then(|duel| find_player(duel.other_player(player.id()), server_state))
+}
+
+fn send_to_other(msg : Arc<Message>, duel : &Duel, current : Id, server_state : &State) -> BasicResult<()> {
+  match find_player(duel.other_player(current), server_state) {
+    Some(other_player) => other_player.outbox.push(msg),
+    None => Err(From::from(format!("Duel opponent of {} is gone", current)))
+  }
+}
+
+// copies are released to the observers by handle_tick after the broadcast delay
+fn feed_observers(msg : &Message, duel : &Duel, current : Id, server_state : &mut State) {
+  let observers = server_state.observers_of(duel);
+  if !observers.is_empty() {
+    let mut body = format!("{}:", current).into_bytes();
+    body.extend_from_slice(&msg.body);
+    let feed_msg = Arc::new(Message::with_body(MessageType::BroadcastFeed, body));
+    let release_at = now_ms() + server_state.config.broadcast_delay_ms;
+    for observer_id in observers {
+      server_state.queue_feed(DelayedMessage { release_at, observer_id, msg : feed_msg.clone() });
+    }
+  }
 }
 
 pub fn send(msg : Arc<Message>, player : &Player) -> BasicResult<()> {
-  let tx = try!(box_err(player.tx.lock()));
-  tx.send(msg).map_err(From::from)
+  player.outbox.push(msg)
+}
+
+// the snapshot brings a player taking deltas up to date with its lobby
+fn answer_snapshot(player : &Player, request : Arc<Message>, server_state : &State) -> BasicResult<()> {
+  let lobby = player.lobby()?;
+  let snapshot = server_state.lobby_snapshot_string(&lobby, &player.blocked()?);
+  if player.list_version()?.is_some() {
+    player.set_list_version(Some(server_state.lobby_version(&lobby)))?;
+  }
+  answer(Message::new(MessageType::ListSnapshot, &snapshot), player, request)
 }
 
 fn answer(msg : Message, player : &Player, request : Arc<Message>) -> BasicResult<()> {
-  let tx= try!(box_err(player.tx.lock()));
   let answer = Message{
     header : Header {
       answer_id : request.header.message_id,
       .. msg.header
     }, .. msg
   };
-  tx.send(Arc::new(answer)).map_err(From::from)
+  player.outbox.push(Arc::new(answer))
+}
+
+fn answer_error(text : &str, player : &Player, request : Arc<Message>) -> BasicResult<()> {
+  warn!("Error to {} : {}", player.id(), text);
+  answer(Message::new(MessageType::Error, text), player, request)
+}
+
+// id:base64 name:text
+fn sender_prefixed(player : &Player, text : &str) -> BasicResult<String> {
+  let state = box_err(player.state.read())?;
+  Ok(format!("{}:{}:{}", player.id(), encode(state.name.as_bytes()), text))
 }
 
 pub fn release_player(player : &Player, server_state : &mut State) -> BasicResult<()> {
-  match server_state.players.iter().position(|p| p.id == player.id) {
-    Some(i) => {
-      server_state.players.remove(i);
-      info!("Remove player {}", player.id);
-    },
-    None => warn!("Failed to find and remove player {}", player.id)
+  match server_state.players.remove(player) {
+    Some(_) => info!("Remove player {}", player.id()),
+    None => {
+      warn!("Failed to find and remove player {}", player.id());
+      return Ok(()) // already released
+    }
   }
-  try!(exit_duel(player));
-  server_state.purge_request(player.id);
-  if let Err(err) = broadcast_list_to_onhold(server_state) {
-    error!("Failed broadcasting release of {} : {}", player.id, err);
+  exit_duel(player, server_state)?;
+  notify_friends(player, PRESENCE_OFFLINE, server_state);
+  server_state.purge_request(player.id());
+  server_state.close_room(player.id());
+  server_state.purge_subscription(player.id());
+  match player.lobby() {
+    Ok(lobby) => update_list(server_state, &lobby),
+    Err(err) => error!("Failed broadcasting release of {} : {}", player.id(), err)
   }
   Ok(())
 }
 
-fn broadcast_list_to_onhold(server_state: &State) -> BasicResult<Vec<Id>> {
-  let p = try!(server_state.player_list_string());
-  broadcast_to_onhold(Arc::new(Message::new(MessageType::ListPlayers, &p)), &server_state)
+fn presence(player : &Player) -> BasicResult<u8> {
+  Ok(if player.is_on_hold()? { PRESENCE_ON_HOLD } else { PRESENCE_DUELLING })
 }
 
-fn broadcast_to_onhold(msg : Arc<Message>, server_state: &State) -> BasicResult<Vec<Id>> {
-  let players_on_hold :Vec<Arc<Player>> = server_state.players.iter()
-    .filter(|p| p.is_on_hold_unsafe())
-    .map(|p| p.clone())
-    .collect();
-  broadcast(msg, &players_on_hold)
+fn friend_presence(name : &str, server_state : &State) -> u8 {
+  find_members_by_name(name, server_state).iter()
+    .filter_map(|p| presence(p).ok())
+    .min()
+    .unwrap_or(PRESENCE_OFFLINE)
+}
+
+fn friend_status_message(name : &str, presence : u8) -> Message {
+  Message::new(MessageType::FriendStatus, &format!("{}:{}", encode(name.as_bytes()), presence))
+}
+
+// a guest may hold the name of an account while it is offline, it is no friend
+fn find_members_by_name(name : &str, server_state : &State) -> Vec<Arc<Player>> {
+  find_players_by_name(name, server_state).into_iter()
+    .filter(|p| p.is_authenticated().unwrap_or(false))
+    .collect()
+}
+
+// base64 name:presence to every connected friend of player
+fn notify_friends(player : &Player, presence : u8, server_state : &State) {
+  let name = match player.name() {
+    Ok(ref name) if !name.is_empty() && player.is_authenticated().unwrap_or(false) => name.clone(),
+    _ => return
+  };
+  let msg = Arc::new(friend_status_message(&name, presence));
+  for friend_name in server_state.friends.friends_of(&name) {
+    for friend in find_members_by_name(&friend_name, server_state) {
+      if let Err(err) = send(msg.clone(), &friend) {
+        error!("Failed notifying {} of {} : {}", friend.id(), player.id(), err);
+      }
+    }
+  }
+}
+
+// lobby changes are broadcast together once the update window has passed
+fn update_list(server_state : &mut State, lobby : &str) {
+  let window = server_state.config.list_update_window_ms;
+  if window == 0 {
+    flush_list(server_state, lobby);
+  } else {
+    server_state.queue_list_update(lobby, now_ms() + window);
+  }
+}
+
+fn flush_lists(server_state : &mut State, now : u64) {
+  for lobby in server_state.pop_due_lists(now) {
+    flush_list(server_state, &lobby);
+  }
+}
+
+fn flush_list(server_state : &mut State, lobby : &str) {
+  match broadcast_list_to_onhold(server_state, lobby) {
+    Ok(to_purge) => check_purge(server_state, to_purge),
+    Err(err) => error!("Failed broadcasting list of lobby {} : {}", lobby, err)
+  }
+}
+
+// players blocking someone get their own list without the blocked players.
+// players taking deltas get the changes since their version, or a snapshot when they missed some
+fn broadcast_list_to_onhold(server_state: &mut State, lobby : &str) -> BasicResult<Vec<Id>> {
+  let deltas = server_state.publish_lobby(lobby)?;
+  let version = server_state.lobby_version(lobby);
+  let base_version = deltas.first().map(|delta| delta.version - 1).unwrap_or(version);
+  let delta_msgs : Vec<Arc<Message>> = deltas.iter().map(|delta| Arc::new(delta_message(delta))).collect();
+  let mut list_msg = None;
+  let mut to_purge = Vec::new();
+  for player in players_on_hold(server_state, lobby) {
+    let blocked = player.blocked()?;
+    let player_msgs = match player.list_version()? {
+      Some(player_version) if player_version == base_version => {
+        deltas.iter().zip(delta_msgs.iter()).map(|(delta, msg)| {
+          if blocked.contains(&delta.id) {
+            Arc::new(delta_message(&ListDelta { version : delta.version, id : delta.id, change : ListChange::Left }))
+          } else {
+            msg.clone()
+          }
+        }).collect()
+      },
+      Some(_) => vec![Arc::new(Message::new(MessageType::ListSnapshot, &server_state.lobby_snapshot_string(lobby, &blocked)))],
+      None if blocked.is_empty() => {
+        if list_msg.is_none() {
+          list_msg = Some(Arc::new(Message::new(MessageType::ListPlayers, &server_state.player_list_string(lobby, &blocked)?)));
+        }
+        list_msg.iter().cloned().collect()
+      },
+      None => vec![Arc::new(Message::new(MessageType::ListPlayers, &server_state.player_list_string(lobby, &blocked)?))]
+    };
+    if player.list_version()?.is_some() {
+      player.set_list_version(Some(version))?;
+    }
+    for player_msg in player_msgs {
+      if let Err(err) = send(player_msg, &player) {
+        error!("Failed sending to {} : {}", player.id(), err);
+        to_purge.push(player.id());
+        break
+      }
+    }
+  }
+  Ok(to_purge)
+}
+
+// version:player string, or version:id when the player left
+fn delta_message(delta : &ListDelta) -> Message {
+  match delta.change {
+    ListChange::Joined(ref entry) => Message::new(MessageType::PlayerJoined, &format!("{}:{}", delta.version, entry)),
+    ListChange::Updated(ref entry) => Message::new(MessageType::PlayerUpdated, &format!("{}:{}", delta.version, entry)),
+    ListChange::Left => Message::new(MessageType::PlayerLeft, &format!("{}:{}", delta.version, delta.id))
+  }
+}
+
+fn players_on_hold(server_state: &State, lobby : &str) -> Vec<Arc<Player>> {
+  server_state.players.on_hold()
+    .filter(|p| p.is_in_lobby_unsafe(lobby)).cloned()
+    .collect()
 }
 
 // return the list of players when send fails
-pub fn broadcast(msg : Arc<Message>, players : &Vec<Arc<Player>>) -> BasicResult<Vec<Id>> {
+pub fn broadcast<'a, I>(msg : Arc<Message>, players : I) -> BasicResult<Vec<Id>>
+    where I : IntoIterator<Item = &'a Arc<Player>> {
   Ok(
-    players.iter()
+      players.into_iter()
       .filter_map(|player| {
-        if let Ok(tx) = box_err(player.tx.lock()) {
-          tx.send(msg.clone()).err().map(|e|{
-            error!("Failed sending to {} : {}", player.id, e);
-            player.id
-          })
-        } else {
-          error!("Failed to lock on tx of player {}" , player.id); // FIXME should return? should be Some(player.id)?
-          None
-        }
+        player.outbox.push(msg.clone()).err().map(|e|{
+          error!("Failed sending to {} : {}", player.id(), e);
+          player.id()
+        })
       })
       .collect())
 }
 
-pub fn check_purge(server_state : &mut State, player_ids : Vec<Id>) -> () {
+pub fn handle_tick(server_state : &mut State) {
+  let now = now_ms();
+  if now - server_state.last_ping >= PING_INTERVAL_MS {
+    server_state.last_ping = now;
+    ping_players(now, server_state);
+  }
+  release_feed(server_state, now);
+  flush_lists(server_state, now);
+  save_accounts(server_state, now);
+}
+
+// the file is written on a blocking task, away from the handler
+fn save_accounts(server_state : &mut State, now : u64) {
+  match server_state.accounts.snapshot(now) {
+    Ok(Some(snapshot)) => {
+      tokio::task::spawn_blocking(move || {
+        if let Err(err) = snapshot.write() {
+          error!("Failed saving accounts : {}", err);
+        }
+      });
+    },
+    Ok(None) => (),
+    Err(err) => error!("Failed taking accounts snapshot : {}", err)
+  }
+}
+
+fn release_feed(server_state : &mut State, now : u64) {
+  let mut to_purge = Vec::new();
+  for delayed in server_state.pop_released_feed(now) {
+    if let Some(observer) = find_player(delayed.observer_id, server_state) {
+      if let Err(err) = send(delayed.msg, &observer) {
+        error!("Failed sending feed to {} : {}", observer.id(), err);
+        to_purge.push(observer.id());
+      }
+    }
+  }
+  check_purge(server_state, to_purge)
+}
+
+fn ping_players(now : u64, server_state : &mut State) {
+  let msg = Arc::new(Message::new(MessageType::Ping, &now.to_string()));
+  match broadcast(msg, server_state.players.iter()) {
+    Ok(to_purge) => check_purge(server_state, to_purge),
+    Err(err) => error!("Failed pinging players {}", err)
+  }
+}
+
+fn now_ms() -> u64 {
+  time::precise_time_ns() / 1000000
+}
+
+pub fn check_purge(server_state : &mut State, player_ids : Vec<Id>) {
   for player_id in player_ids.iter() {
-    let player_option = server_state.players.iter()
-      .find(|p| p.id == *player_id)
-      .map( |p| p.clone());
+    let player_option = server_state.players.get(*player_id).cloned();
     if let Some(p) = player_option {
       if let Err(err) = release_player(&p, server_state) {
         error!("Failed release player {}: {}", player_id, err);
       } 
     }
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+  use std::ops::{Deref, DerefMut};
+  use std::sync::{Arc, Mutex, Weak};
+  use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+  use crate::bans::Bans;
+  use crate::config::Config;
+  use crate::outbox::Outbox;
+  use super::*;
+
+  fn new_player(server_state : &mut State) -> Arc<Player> {
+    let player = Arc::new(Player::new(Outbox::new(64, 1024), None));
+    handle_msg(HandlerMessage::AddPlayer, player.clone(), server_state).unwrap();
+    player
+  }
+
+  fn request_duel(player : &Arc<Player>, other : &Player, server_state : &mut State) {
+    let msg = Arc::new(Message::new(MessageType::RequestDuel, &other.id().to_string()));
+    handle_msg(HandlerMessage::ClientMessage(msg), player.clone(), server_state).unwrap();
+  }
+
+  fn client_msg(player : &Arc<Player>, message_type : MessageType::Value, body : &str, server_state : &mut State) {
+    let msg = Arc::new(Message::new(message_type, body));
+    handle_msg(HandlerMessage::ClientMessage(msg), player.clone(), server_state).unwrap();
+  }
+
+  fn received(player : &Player) -> Vec<(MessageType::Value, String)> {
+    player.outbox.drain().unwrap().iter()
+      .map(|msg| (msg.header.message_type, msg.body_as_str().unwrap().to_string()))
+      .collect()
+  }
+
+  fn start_duel(server_state : &mut State) -> (Weak<Player>, Weak<Player>) {
+    let player1 = new_player(server_state);
+    let player2 = new_player(server_state);
+    request_duel(&player1, &player2, server_state);
+    request_duel(&player2, &player1, server_state);
+    assert!(server_state.duel_of(player1.id()).is_some());
+    (Arc::downgrade(&player1), Arc::downgrade(&player2))
+  }
+
+  // fixed settings, whatever the environment of the test run
+  fn test_config() -> Config {
+    Config {
+      observer_key        : None,
+      broadcast_delay_ms  : 30000,
+      data_dir            : std::path::PathBuf::new(), // a fresh one is given by new_state_with
+      auth_key            : None,
+      require_auth        : false,
+      duplicate_session   : DuplicateSessionPolicy::RejectNew,
+      admins              : Vec::new(),
+      rate_limits         : HashMap::new(),
+      max_rate_violations : 20.0,
+      max_connections     : 10000,
+      max_connections_per_ip : 20,
+      retry_after_secs    : 30,
+      outbox_high_water   : 64,
+      outbox_capacity     : 1024,
+      max_message_length  : 1 << 20,
+      list_update_window_ms : 200
+    }
+  }
+
+  // the state with its data dir, removed at the end of the test
+  struct TestState {
+    state     : State,
+    _data_dir : TempDir
+  }
+
+  impl Deref for TestState {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+      &self.state
+    }
+  }
+
+  impl DerefMut for TestState {
+    fn deref_mut(&mut self) -> &mut State {
+      &mut self.state
+    }
+  }
+
+  // the receiver gets what is sent back to the handler
+  fn new_state_with(mut config : Config) -> (TestState, UnboundedReceiver<HandlerParam>) {
+    let data_dir = TempDir::new();
+    config.data_dir = data_dir.0.clone();
+    let bans = Bans::empty(config.data_dir.join("bans"));
+    let (handler_tx, handler_rx) = unbounded_channel();
+    let state = State::new(config, Arc::new(Mutex::new(bans)), handler_tx).unwrap();
+    (TestState { state, _data_dir : data_dir }, handler_rx)
+  }
+
+  fn new_state() -> TestState {
+    new_state_with(test_config()).0
+  }
+
+  async fn handle_next(handler_rx : &mut UnboundedReceiver<HandlerParam>, server_state : &mut State) {
+    let (handler_msg, player) = handler_rx.recv().await.unwrap();
+    handle_msg(handler_msg, player, server_state).unwrap();
+  }
+
+  #[test]
+  fn released_duellists_are_freed() {
+    let mut server_state = new_state();
+    let (player1, player2) = start_duel(&mut server_state);
+    for player in [&player1, &player2].iter() {
+      let player = player.upgrade().unwrap();
+      handle_msg(HandlerMessage::ReleasePlayer, player, &mut server_state).unwrap();
+    }
+    assert!(player1.upgrade().is_none());
+    assert!(player2.upgrade().is_none());
+    assert!(server_state.duels.is_empty());
+  }
+
+  #[test]
+  fn duellist_removed_without_cleanup_is_freed() {
+    let mut server_state = new_state();
+    let (player1, player2) = start_duel(&mut server_state);
+    let removed = player1.upgrade().unwrap();
+    server_state.players.remove(&removed);
+    drop(removed);
+    // the opponent and the duel table must not keep it alive
+    assert!(player1.upgrade().is_none());
+    assert!(player2.upgrade().is_some());
+  }
+
+  #[test]
+  fn list_deltas_follow_lobby_changes() {
+    let mut server_state = new_state();
+    let watcher = new_player(&mut server_state);
+    client_msg(&watcher, MessageType::ListDeltas, "1", &mut server_state);
+    assert_eq!(received(&watcher).last().unwrap(), &(MessageType::ListSnapshot, "0".to_string()));
+
+    let player = new_player(&mut server_state);
+    client_msg(&player, MessageType::Name, "toto", &mut server_state);
+    flush_lists(&mut server_state, u64::MAX);
+    let entry = format!("{}:0:{}:0", encode("toto".as_bytes()), player.id());
+    assert_eq!(received(&watcher), vec![(MessageType::PlayerJoined, format!("1:{}", entry))]);
+
+    client_msg(&player, MessageType::Name, "titi", &mut server_state);
+    flush_lists(&mut server_state, u64::MAX);
+    let entry = format!("{}:0:{}:0", encode("titi".as_bytes()), player.id());
+    assert_eq!(received(&watcher), vec![(MessageType::PlayerUpdated, format!("2:{}", entry))]);
+
+    handle_msg(HandlerMessage::ReleasePlayer, player.clone(), &mut server_state).unwrap();
+    flush_lists(&mut server_state, u64::MAX);
+    assert_eq!(received(&watcher), vec![(MessageType::PlayerLeft, format!("3:{}", player.id()))]);
+
+    // the version goes on once the lobby list was empty
+    let player = new_player(&mut server_state);
+    client_msg(&player, MessageType::Name, "tata", &mut server_state);
+    flush_lists(&mut server_state, u64::MAX);
+    let entry = format!("{}:0:{}:0", encode("tata".as_bytes()), player.id());
+    assert_eq!(received(&watcher), vec![(MessageType::PlayerJoined, format!("4:{}", entry))]);
+  }
+
+  #[test]
+  fn empty_lobby_lists_are_dropped() {
+    let mut server_state = new_state();
+    let player = new_player(&mut server_state);
+    client_msg(&player, MessageType::Name, "toto", &mut server_state);
+    for lobby in ["a", "b", "c"].iter() {
+      client_msg(&player, MessageType::JoinLobby, lobby, &mut server_state);
+      flush_lists(&mut server_state, u64::MAX);
+    }
+    assert_eq!(server_state.lobby_lists.keys().collect::<Vec<_>>(), vec!["c"]);
+
+    // a lobby coming back starts after every version given so far
+    let version = server_state.last_list_version;
+    assert_eq!(server_state.lobby_version("a"), version);
+    let watcher = new_player(&mut server_state);
+    client_msg(&watcher, MessageType::JoinLobby, "a", &mut server_state);
+    client_msg(&watcher, MessageType::ListDeltas, "1", &mut server_state);
+    assert_eq!(received(&watcher).last().unwrap(), &(MessageType::ListSnapshot, version.to_string()));
+    // unnamed players are not listed, so only lobby a changes
+    let other = new_player(&mut server_state);
+    client_msg(&other, MessageType::JoinLobby, "a", &mut server_state);
+    client_msg(&other, MessageType::Name, "tata", &mut server_state);
+    flush_lists(&mut server_state, u64::MAX);
+    let entry = format!("{}:0:{}:0", encode("tata".as_bytes()), other.id());
+    assert_eq!(received(&watcher), vec![(MessageType::PlayerJoined, format!("{}:{}", version + 1, entry))]);
+  }
+
+  #[test]
+  fn lobby_changes_make_one_list_update() {
+    let mut server_state = new_state();
+    let watcher = new_player(&mut server_state);
+    for name in ["toto", "titi", "tata"].iter() {
+      let player = new_player(&mut server_state);
+      client_msg(&player, MessageType::Name, name, &mut server_state);
+    }
+    assert!(received(&watcher).is_empty());
+    flush_lists(&mut server_state, u64::MAX);
+    let updates = received(&watcher);
+    assert_eq!(updates.len(), 1);
+    assert_eq!(updates[0].0, MessageType::ListPlayers);
+    assert_eq!(updates[0].1.split(';').count(), 3);
+  }
+
+  #[test]
+  fn list_query_filters_sorts_and_pages() {
+    let mut server_state = new_state();
+    let watcher = new_player(&mut server_state);
+    for name in ["tata", "titi", "toto", "bob"].iter() {
+      let player = new_player(&mut server_state);
+      client_msg(&player, MessageType::Name, name, &mut server_state);
+    }
+    received(&watcher);
+    let query = format!("prefix:{};sort:-name;limit:2", encode("T".as_bytes()));
+    client_msg(&watcher, MessageType::ListPlayers, &query, &mut server_state);
+    let (message_type, body) = received(&watcher).pop().unwrap();
+    assert_eq!(message_type, MessageType::ListPlayers);
+    let fields : Vec<&str> = body.split(';').collect();
+    assert_eq!(fields.len(), 3);
+    assert_eq!(fields[0], "3");
+    assert!(fields[1].starts_with(&encode("toto".as_bytes())));
+    assert!(fields[2].starts_with(&encode("titi".as_bytes())));
+
+    client_msg(&watcher, MessageType::ListPlayers, "sort:name;page:1;limit:3", &mut server_state);
+    let (_, body) = received(&watcher).pop().unwrap();
+    let fields : Vec<&str> = body.split(';').collect();
+    assert_eq!(fields.len(), 2);
+    assert_eq!(fields[0], "4");
+    assert!(fields[1].starts_with(&encode("toto".as_bytes())));
+
+    client_msg(&watcher, MessageType::ListPlayers, "status:2", &mut server_state);
+    assert_eq!(received(&watcher).pop().unwrap().0, MessageType::Error);
+  }
+
+  #[test]
+  fn duel_requested_by_name() {
+    let mut server_state = new_state();
+    let player = new_player(&mut server_state);
+    let other = new_player(&mut server_state);
+    client_msg(&other, MessageType::Name, "toto", &mut server_state);
+    received(&player);
+
+    client_msg(&player, MessageType::FindPlayer, "titi", &mut server_state);
+    assert_eq!(received(&player).pop().unwrap().0, MessageType::Error);
+    client_msg(&player, MessageType::FindPlayer, "toto", &mut server_state);
+    let (message_type, body) = received(&player).pop().unwrap();
+    assert_eq!(message_type, MessageType::FindPlayer);
+    assert!(body.contains(&format!(":{}:", other.id())));
+
+    client_msg(&player, MessageType::RequestDuelByName, "toto", &mut server_state);
+    assert_eq!(received(&other).pop().unwrap(), (MessageType::RequestDuel, player.id().to_string()));
+  }
+
+  #[test]
+  fn private_duel_joined_by_code() {
+    let mut server_state = new_state();
+    let creator = new_player(&mut server_state);
+    let friend = new_player(&mut server_state);
+    client_msg(&creator, MessageType::Name, "toto", &mut server_state);
+    client_msg(&creator, MessageType::CreatePrivateDuel, &format!("1:{}", encode("secret".as_bytes())), &mut server_state);
+    let (message_type, code) = received(&creator).pop().unwrap();
+    assert_eq!(message_type, MessageType::CreatePrivateDuel);
+    assert_eq!(code.len(), 6);
+    client_msg(&friend, MessageType::ListPlayers, "", &mut server_state);
+    assert_eq!(received(&friend).pop().unwrap(), (MessageType::ListPlayers, String::new()));
+
+    client_msg(&friend, MessageType::JoinByCode, &code, &mut server_state);
+    let wrong_password = received(&friend).pop().unwrap();
+    client_msg(&friend, MessageType::JoinByCode, "AAAAAA", &mut server_state);
+    assert_eq!(received(&friend).pop().unwrap(), wrong_password);
+    assert_eq!(wrong_password.0, MessageType::Error);
+    client_msg(&friend, MessageType::JoinByCode, &format!("{}:{}", code.to_lowercase(), encode("secret".as_bytes())), &mut server_state);
+    assert_eq!(server_state.duel_of(friend.id()).map(|duel| duel.other_player(friend.id())), Some(creator.id()));
+    assert!(server_state.room(&code).is_none());
+  }
+
+  #[test]
+  fn only_the_last_ping_is_measured() {
+    let mut server_state = new_state();
+    let player = new_player(&mut server_state);
+    server_state.last_ping = now_ms() - 50;
+    let rtt = || player.state.read().unwrap().rtt;
+    client_msg(&player, MessageType::Ping, "0", &mut server_state);
+    client_msg(&player, MessageType::Ping, &(server_state.last_ping - PING_INTERVAL_MS).to_string(), &mut server_state);
+    assert_eq!(rtt(), 0);
+    client_msg(&player, MessageType::Ping, &server_state.last_ping.to_string(), &mut server_state);
+    assert!(rtt() >= 50 && rtt() < MAX_RTT_MS as u32);
+  }
+
+  #[tokio::test]
+  async fn passwords_are_checked_off_the_handler() {
+    let (mut server_state, mut handler_rx) = new_state_with(test_config());
+    let player = new_player(&mut server_state);
+    let credentials = format!("{}:secret", encode("toto".as_bytes()));
+    client_msg(&player, MessageType::Register, &credentials, &mut server_state);
+    assert!(received(&player).is_empty());
+    handle_next(&mut handler_rx, &mut server_state).await;
+    assert_eq!(received(&player).pop().unwrap().0, MessageType::Login);
+    assert!(server_state.accounts.exists("toto"));
+
+    let other = new_player(&mut server_state);
+    client_msg(&other, MessageType::Login, &format!("{}:wrong", encode("toto".as_bytes())), &mut server_state);
+    handle_next(&mut handler_rx, &mut server_state).await;
+    assert_eq!(received(&other).pop().unwrap(), (MessageType::Error, "Invalid name or password".to_string()));
+  }
+
+  #[tokio::test]
+  async fn account_is_not_saved_when_its_name_is_in_use() {
+    let (mut server_state, mut handler_rx) = new_state_with(test_config());
+    let guest = new_player(&mut server_state);
+    client_msg(&guest, MessageType::Name, "toto", &mut server_state);
+    let player = new_player(&mut server_state);
+    let credentials = format!("{}:secret", encode("titi".as_bytes()));
+    client_msg(&player, MessageType::Register, &credentials, &mut server_state);
+    // the guest takes the name while the password is hashed
+    client_msg(&guest, MessageType::Name, "titi", &mut server_state);
+    handle_next(&mut handler_rx, &mut server_state).await;
+    assert_eq!(received(&player).pop().unwrap().0, MessageType::Error);
+    assert!(!server_state.accounts.exists("titi"));
+  }
+
+  #[test]
+  fn duelling_guest_holding_the_name_is_disconnected() {
+    let mut config = test_config();
+    config.duplicate_session = DuplicateSessionPolicy::ReplaceOld;
+    let (mut server_state, _) = new_state_with(config);
+    let (guest, opponent) = start_duel(&mut server_state);
+    let guest = guest.upgrade().unwrap();
+    server_state.players.set_name(&guest, "toto".to_string()).unwrap();
+    let player = new_player(&mut server_state);
+    assert!(matches!(claim_identity(&player, 42, "toto".to_string(), &mut server_state).unwrap(), Claim::Claimed));
+    assert!(guest.is_closed());
+    assert_eq!(received(&guest).pop().unwrap(), (MessageType::Disconnected, "Name claimed by its account".to_string()));
+    assert!(server_state.duel_of(opponent.upgrade().unwrap().id()).is_none());
+    assert!(server_state.players.get(42).is_some());
+    assert_eq!(player.name().unwrap(), "toto");
+  }
+
+  #[test]
+  fn authenticated_player_holding_the_name_is_not_replaced() {
+    let (mut server_state, _) = new_state_with(test_config());
+    let member = new_player(&mut server_state);
+    assert!(matches!(claim_identity(&member, 7, "toto".to_string(), &mut server_state).unwrap(), Claim::Claimed));
+    let player = new_player(&mut server_state);
+    assert!(matches!(claim_identity(&player, 42, "toto".to_string(), &mut server_state).unwrap(), Claim::NameInUse));
+    assert!(!member.is_closed());
+    assert!(server_state.players.get(42).is_none());
+  }
+
+  #[test]
+  fn guest_holding_the_name_stays_when_the_claim_is_rejected() {
+    let (mut server_state, _) = new_state_with(test_config());
+    let session = new_player(&mut server_state);
+    // the backend name changed since the live session logged in
+    claim_identity(&session, 42, "titi".to_string(), &mut server_state).unwrap();
+    let guest = new_player(&mut server_state);
+    client_msg(&guest, MessageType::Name, "toto", &mut server_state);
+    let player = new_player(&mut server_state);
+    assert!(matches!(claim_identity(&player, 42, "toto".to_string(), &mut server_state).unwrap(), Claim::AlreadyConnected));
+    assert!(!guest.is_closed());
+  }
+
+  fn sign_token(payload : &str, key : &[u8]) -> String {
+    use hmac::{Hmac, Mac};
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).unwrap();
+    mac.update(payload.as_bytes());
+    format!("{}.{}", encode(payload.as_bytes()), encode(&mac.finalize().into_bytes()))
+  }
+
+  #[test]
+  fn backend_users_do_not_take_local_identities() {
+    let mut config = test_config();
+    config.auth_key = Some(b"key".to_vec());
+    let (mut server_state, _) = new_state_with(config);
+    let account = server_state.accounts.register("toto", Credentials::new("secret")).unwrap();
+    assert!(account.id & LOCAL_ID_FLAG != 0);
+    let player = new_player(&mut server_state);
+    let expiry = time::get_time().sec + 60;
+    for payload in [format!("{}:{}:titi", account.id, expiry), format!("7:{}:toto", expiry)].iter() {
+      client_msg(&player, MessageType::Auth, &sign_token(payload, b"key"), &mut server_state);
+      assert_eq!(received(&player).pop().unwrap().0, MessageType::Error);
+    }
+    client_msg(&player, MessageType::Auth, &sign_token(&format!("7:{}:titi", expiry), b"key"), &mut server_state);
+    assert_eq!(received(&player).pop().unwrap().0, MessageType::Auth);
+    assert_eq!(player.id(), 7);
+  }
+
+  #[test]
+  fn duel_starts_only_on_mutual_requests() {
+    let mut server_state = new_state();
+    let player1 = new_player(&mut server_state);
+    let player2 = new_player(&mut server_state);
+    let player3 = new_player(&mut server_state);
+    request_duel(&player1, &player3, &mut server_state);
+    request_duel(&player2, &player1, &mut server_state);
+    assert!(server_state.duel_of(player1.id()).is_none());
+    request_duel(&player1, &player2, &mut server_state);
+    assert_eq!(server_state.duel_of(player1.id()).map(|duel| duel.other_player(player1.id())), Some(player2.id()));
+  }
+
+  #[test]
+  fn admin_login_failures_disconnect() {
+    let mut config = test_config();
+    config.require_auth = true;
+    config.admins = vec![("admin".to_string(), "secret".to_string())];
+    let (mut server_state, _) = new_state_with(config);
+    let player = new_player(&mut server_state);
+    let attempt = |password : &str| format!("{}:{}", encode("admin".as_bytes()), password);
+    client_msg(&player, MessageType::AdminLogin, &attempt("secret"), &mut server_state);
+    assert_eq!(received(&player).pop().unwrap().0, MessageType::AdminLogin);
+
+    let player = new_player(&mut server_state);
+    for _ in 0..MAX_ADMIN_LOGIN_FAILURES {
+      client_msg(&player, MessageType::AdminLogin, &attempt("guess"), &mut server_state);
+    }
+    assert_eq!(received(&player).pop().unwrap().0, MessageType::Disconnected);
+    assert!(player.is_closed());
+  }
+
+  #[test]
+  fn ban_durations_out_of_range_are_refused() {
+    let mut config = test_config();
+    config.admins = vec![("admin".to_string(), "secret".to_string())];
+    let (mut server_state, _) = new_state_with(config);
+    let admin = new_player(&mut server_state);
+    client_msg(&admin, MessageType::AdminLogin, &format!("{}:secret", encode("admin".as_bytes())), &mut server_state);
+    received(&admin);
+    for duration in ["0", "-60", "soon"].iter() {
+      client_msg(&admin, MessageType::Ban, &format!("7;{}", duration), &mut server_state);
+      assert_eq!(received(&admin).pop().unwrap().0, MessageType::Error);
+    }
+    client_msg(&admin, MessageType::Ban, &format!("7;{}", i64::MAX), &mut server_state);
+    assert_eq!(received(&admin).pop().unwrap().0, MessageType::Error);
+    assert!(!server_state.bans.lock().unwrap().is_id_banned(7));
+  }
+
+  #[test]
+  fn dump_leaves_out_secrets() {
+    let mut config = test_config();
+    config.observer_key = Some("observer-secret".to_string());
+    config.auth_key = Some(b"auth-secret".to_vec());
+    config.admins = vec![("admin".to_string(), "admin-secret".to_string())];
+    let (mut server_state, _) = new_state_with(config);
+    server_state.accounts.register("toto", Credentials::new("secret")).unwrap();
+    server_state.open_room(PrivateRoom { creator : 1, password : Some("room-secret".to_string()), hidden : false });
+    let dump = format!("{:?}", *server_state);
+    for secret in ["observer-secret", "auth-secret", "admin-secret", "room-secret", "salt", "hash"].iter() {
+      assert!(!dump.contains(secret), "{} in the dump", secret);
+    }
+    assert!(dump.contains("admin"));
+  }
+
+  #[test]
+  fn wrong_observer_keys_disconnect() {
+    let mut config = test_config();
+    config.observer_key = Some("key".to_string());
+    let (mut server_state, _) = new_state_with(config);
+    let (player1, _) = start_duel(&mut server_state);
+    let watch = |key : &str| format!("{}:{}", key, player1.upgrade().unwrap().id());
+    let observer = new_player(&mut server_state);
+    for _ in 0..MAX_OBSERVER_KEY_FAILURES {
+      client_msg(&observer, MessageType::WatchDuel, &watch("guess"), &mut server_state);
+    }
+    assert_eq!(received(&observer).pop().unwrap().0, MessageType::Disconnected);
+    assert!(observer.is_closed());
+    assert!(server_state.subscriptions.is_empty());
+  }
+
+  #[test]
+  fn watching_a_duel_again_adds_no_subscription() {
+    let mut config = test_config();
+    config.observer_key = Some("key".to_string());
+    let (mut server_state, _) = new_state_with(config);
+    let (player1, player2) = start_duel(&mut server_state);
+    let (player1, player2) = (player1.upgrade().unwrap(), player2.upgrade().unwrap());
+    let observer = new_player(&mut server_state);
+    for watched in [&player1, &player1, &player2].iter() {
+      client_msg(&observer, MessageType::WatchDuel, &format!("key:{}", watched.id()), &mut server_state);
+    }
+    assert_eq!(server_state.subscriptions.len(), 2);
+    let duel = server_state.duel_of(player1.id()).unwrap();
+    assert_eq!(server_state.observers_of(&duel), vec![observer.id()]);
+  }
+
+  #[test]
+  fn proxy_out_of_duel_stays_in_the_lobby() {
+    let mut server_state = new_state();
+    let (duellist, _) = start_duel(&mut server_state);
+    let duellist = duellist.upgrade().unwrap();
+    let sender = new_player(&mut server_state);
+    let neighbour = new_player(&mut server_state);
+    let blocker = new_player(&mut server_state);
+    client_msg(&blocker, MessageType::Block, &sender.id().to_string(), &mut server_state);
+    let elsewhere = new_player(&mut server_state);
+    client_msg(&elsewhere, MessageType::JoinLobby, "other", &mut server_state);
+    for player in [&duellist, &neighbour, &blocker, &elsewhere].iter() {
+      received(player);
+    }
+    client_msg(&sender, MessageType::Proxy, "hello", &mut server_state);
+    assert_eq!(received(&neighbour), vec![(MessageType::Proxy, "hello".to_string())]);
+    assert!(received(&duellist).is_empty());
+    assert!(received(&blocker).is_empty());
+    assert!(received(&elsewhere).is_empty());
+  }
+
+  #[test]
+  fn blocked_players_are_capped() {
+    let mut server_state = new_state();
+    let player = new_player(&mut server_state);
+    for id in 0..MAX_BLOCKED_PLAYERS {
+      client_msg(&player, MessageType::Block, &id.to_string(), &mut server_state);
+    }
+    assert!(received(&player).is_empty());
+    client_msg(&player, MessageType::Block, "0", &mut server_state);
+    client_msg(&player, MessageType::Block, &MAX_BLOCKED_PLAYERS.to_string(), &mut server_state);
+    assert_eq!(received(&player), vec![(MessageType::Error, format!("Cannot block more than {} players", MAX_BLOCKED_PLAYERS))]);
+    assert_eq!(player.blocked_count().unwrap(), MAX_BLOCKED_PLAYERS);
+    client_msg(&player, MessageType::Unblock, "0", &mut server_state);
+    client_msg(&player, MessageType::Block, &MAX_BLOCKED_PLAYERS.to_string(), &mut server_state);
+    assert!(received(&player).is_empty());
+  }
+
+  #[test]
+  fn guests_have_no_friends() {
+    let mut server_state = new_state();
+    server_state.friends.request("toto", "titi").unwrap();
+    server_state.friends.request("titi", "toto").unwrap();
+    // a guest holding the name of an offline account
+    let guest = new_player(&mut server_state);
+    client_msg(&guest, MessageType::Name, "titi", &mut server_state);
+    client_msg(&guest, MessageType::ListFriends, "", &mut server_state);
+    assert_eq!(received(&guest).pop().unwrap(), (MessageType::Error, "Friends need an account".to_string()));
+
+    let member = new_player(&mut server_state);
+    server_state.players.authenticate(&member, "toto".to_string()).unwrap();
+    notify_friends(&member, PRESENCE_ON_HOLD, &server_state);
+    assert!(received(&guest).iter().all(|(message_type, _)| *message_type != MessageType::FriendStatus));
+  }
+
+  #[test]
+  fn whisper_to_malformed_id_is_answered() {
+    let mut server_state = new_state();
+    let player = new_player(&mut server_state);
+    received(&player);
+    client_msg(&player, MessageType::Whisper, "bob:hi", &mut server_state);
+    assert_eq!(received(&player), vec![(MessageType::Error, "Whisper expects dest id:text".to_string())]);
+  }
+}
@@ -0,0 +1,139 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::Write;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use base64::{encode, decode};
+
+use crate::utils::*;
+
+pub const PRESENCE_ON_HOLD  : u8 = 0;
+pub const PRESENCE_DUELLING : u8 = 1;
+pub const PRESENCE_OFFLINE  : u8 = 2;
+
+pub enum FriendRequestOutcome {
+  Requested,
+  Accepted,
+  AlreadyFriends
+}
+
+// friendships are persisted as one line per pair of base64 names, pending requests are kept in memory
+#[derive(Debug)]
+pub struct Friends {
+  path        : PathBuf,
+  friendships : BTreeSet<(String, String)>,
+  requests    : HashSet<(String, String)>
+}
+
+impl Friends {
+
+  // a line that cannot be read is skipped, failing to read the file fails the load
+  pub fn load(path : PathBuf) -> BasicResult<Friends> {
+    let mut friends = Friends {
+      path,
+      friendships : BTreeSet::new(),
+      requests    : HashSet::new()
+    };
+    if friends.path.exists() {
+      let file = File::open(&friends.path)?;
+      for line in BufReader::new(file).lines() {
+        let line = line?;
+        match parse_friendship(&line) {
+          Ok(friendship) => { friends.friendships.insert(friendship); },
+          Err(err) => warn!("Ignored friendship line {} : {}", line, err)
+        }
+      }
+      info!("Loaded {} friendships from {:?}", friends.friendships.len(), friends.path);
+    }
+    Ok(friends)
+  }
+
+  // friendship is made when both players requested it
+  pub fn request(&mut self, from : &str, to : &str) -> BasicResult<FriendRequestOutcome> {
+    if self.are_friends(from, to) {
+      Ok(FriendRequestOutcome::AlreadyFriends)
+    } else if self.requests.remove(&(to.to_string(), from.to_string())) {
+      self.friendships.insert(pair(from.to_string(), to.to_string()));
+      self.save()?;
+      Ok(FriendRequestOutcome::Accepted)
+    } else {
+      self.requests.insert((from.to_string(), to.to_string()));
+      Ok(FriendRequestOutcome::Requested)
+    }
+  }
+
+  pub fn remove(&mut self, name1 : &str, name2 : &str) -> BasicResult<bool> {
+    self.requests.remove(&(name1.to_string(), name2.to_string()));
+    if self.friendships.remove(&pair(name1.to_string(), name2.to_string())) {
+      self.save()?;
+      Ok(true)
+    } else {
+      Ok(false)
+    }
+  }
+
+  pub fn are_friends(&self, name1 : &str, name2 : &str) -> bool {
+    self.friendships.contains(&pair(name1.to_string(), name2.to_string()))
+  }
+
+  pub fn friends_of(&self, name : &str) -> Vec<String> {
+    self.friendships.iter()
+      .filter_map(|(name1, name2)| {
+        if name1 == name {
+          Some(name2.clone())
+        } else if name2 == name {
+          Some(name1.clone())
+        } else {
+          None
+        }
+      })
+      .collect()
+  }
+
+  fn save(&self) -> BasicResult<()> {
+    let mut contents = String::new();
+    for (name1, name2) in self.friendships.iter() {
+      writeln!(contents, "{};{}", encode(name1.as_bytes()), encode(name2.as_bytes()))?;
+    }
+    Ok(write_atomically(&self.path, &contents)?)
+  }
+}
+
+fn parse_friendship(line : &str) -> BasicResult<(String, String)> {
+  let names : Vec<&str> = line.split(';').collect();
+  if names.len() != 2 {
+    return Err(From::from(format!("{} names instead of 2", names.len())))
+  }
+  let name1 = String::from_utf8(decode(names[0])?)?;
+  let name2 = String::from_utf8(decode(names[1])?)?;
+  Ok(pair(name1, name2))
+}
+
+fn pair(name1 : String, name2 : String) -> (String, String) {
+  if name1 <= name2 {
+    (name1, name2)
+  } else {
+    (name2, name1)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bad_lines_are_skipped_and_kept_out_of_the_next_save() {
+    let dir = TempDir::new();
+    let path = dir.0.join("friends");
+    let valid = format!("{};{}", encode("toto".as_bytes()), encode("titi".as_bytes()));
+    std::fs::write(&path, format!("{}\n!!bad;!!bad\n", valid)).unwrap();
+    let mut friends = Friends::load(path.clone()).unwrap();
+    assert!(friends.are_friends("titi", "toto"));
+
+    friends.request("tata", "toto").unwrap();
+    friends.request("toto", "tata").unwrap();
+    let reloaded = Friends::load(path).unwrap();
+    assert_eq!(reloaded.friends_of("toto"), vec!["tata".to_string(), "titi".to_string()]);
+    assert_eq!(reloaded.friendships.len(), 2);
+  }
+}
@@ -1,43 +1,56 @@
 #![crate_name = "fserve"]
 
-extern crate either;
 #[macro_use] extern crate log;
-extern crate env_logger;
-extern crate mio;
-#[macro_use] extern crate mioco;
-extern crate rand;
-extern crate base64;
-extern crate time;
 
+mod accounts;
+mod auth;
+mod bans;
+mod config;
+mod connections;
 mod controller;
+mod friends;
 mod model;
+mod outbox;
+mod ratelimit;
+mod registry;
 mod messagebuilder;
 mod state;
 mod utils;
+mod writer;
 
-use either::*;
-use mio::tcp::TcpStream;
-use mioco::tcp::TcpListener;
-use mioco::MioAdapter;
-use mioco::sync::Mutex;
-use mioco::sync::mpsc::{channel, Receiver, Sender};
 use std::env;
-use log::{LogRecord, LogLevelFilter};
-use env_logger::LogBuilder;
-use std::io::prelude::*;
-use std::io;
+use std::io::{self, Write};
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::sync::mpsc::TryRecvError;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use messagebuilder::*;
-use controller::HandlerMessage;
-use model::*;
-use state::State;
-use utils::*;
+use std::time::Duration;
+use env_logger::Builder;
+use log::LevelFilter;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{interval, sleep, MissedTickBehavior};
+use crate::messagebuilder::*;
+use crate::bans::Bans;
+use crate::config::Config;
+use crate::connections::Connections;
+use crate::controller::{HandlerMessage, HandlerParam};
+use crate::outbox::Outbox;
+use crate::ratelimit::{RateDecision, RateLimiter};
+use crate::model::*;
+use crate::state::State;
+use crate::utils::*;
+use crate::writer::Writer;
 
-type HandlerParam = (HandlerMessage, Arc<Player>);
+const TICK_MS : u64 = 100;
+// the last messages before closing, a peer that does not read them is dropped
+const CLOSE_FLUSH_TIMEOUT_MS : u64 = 5000;
+// out of file descriptors, accepting again at once would fail the same way
+const ACCEPT_RETRY_MS : u64 = 100;
+const ENFILE : i32 = 23;
+const EMFILE : i32 = 24;
 
 fn listend_addr() -> SocketAddr {
   let port = env::args().nth(1).unwrap_or("12345".to_string());
@@ -48,152 +61,268 @@ fn listend_addr() -> SocketAddr {
 
 pub fn run_server() {
   init_logger();
-  let (handler_tx, handler_rx) = channel::<HandlerParam>();
+  let config = Config::from_env();
+  let (handler_tx, handler_rx) = unbounded_channel::<HandlerParam>();
+  let bans_path = config.data_dir.join("bans");
+  // an empty list saved over bans that failed to load would lift them all
+  let bans = Arc::new(Mutex::new(Bans::load(bans_path.clone()).unwrap_or_else(|err| {
+    panic!("Failed loading bans from {:?} : {}", bans_path, err)
+  })));
 
-  start_handler(handler_rx);
-  start_listen(Arc::new(Mutex::new(handler_tx)));
+  let server_state = State::new(config.clone(), bans.clone(), handler_tx.clone()).unwrap_or_else(|err| panic!("{}", err));
+  start_handler(handler_rx, server_state);
+  let runtime = runtime::Runtime::new().unwrap();
+  runtime.block_on(listen(handler_tx, bans, config)).unwrap();
 }
 
-fn start_handler(handler_rx : Receiver<HandlerParam>) {
+// the handler owns the server state on its own thread, away from the connections.
+// it must not block : password hashing runs on blocking tasks that send their outcome back through handler_tx,
+// and the accounts are written on blocking tasks too
+fn start_handler(handler_rx : UnboundedReceiver<HandlerParam>, server_state : State) {
   thread::spawn(move|| {
     info!("Start handler");
-    mioco::start_threads(1, move || -> io::Result<()> {
-      let mut server_state = State::new();
-      loop {
-        let (message, player) = try!(map_io_err(handler_rx.recv()));
-        if let Err(err) = controller::handle_msg(message, player, &mut server_state) {
-          error!("Failed handling msg {}", err);
-        }
-      }
-    }).unwrap().unwrap();
+    let runtime = runtime::Builder::new_current_thread().enable_time().build().unwrap();
+    runtime.block_on(handle(handler_rx, server_state));
   });
 }
 
-fn start_listen(handler_tx : Arc<Mutex<Sender<HandlerParam>>>) {
-  mioco::start(move || -> io::Result<()> {
-    let addr = listend_addr();
-    let listener = try!(TcpListener::bind(&addr));
+async fn handle(mut handler_rx : UnboundedReceiver<HandlerParam>, mut server_state : State) {
+  let mut tick = interval(Duration::from_millis(TICK_MS));
+  tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+  loop {
+    tokio::select! {
+      param = handler_rx.recv() => match param {
+        Some((message, player)) => {
+          if let Err(err) = controller::handle_msg(message, player, &mut server_state) {
+            error!("Failed handling msg {}", err);
+          }
+        },
+        None => {
+          error!("Handler channel disconnected");
+          return
+        }
+      },
+      _ = tick.tick() => controller::handle_tick(&mut server_state),
+    }
+  }
+}
+
+async fn listen(handler_tx : UnboundedSender<HandlerParam>, bans : Arc<Mutex<Bans>>, config : Config) -> io::Result<()> {
+  let addr = listend_addr();
+  let listener = TcpListener::bind(&addr).await?;
 
-    info!("Starting tcp server on {:?}", try!(listener.local_addr()));
+  info!("Starting tcp server on {:?}", listener.local_addr()?);
+  let connections = Arc::new(Mutex::new(Connections::new(config.max_connections, config.max_connections_per_ip)));
 
-    loop {
-      let mut conn = try!(listener.accept());
-      let handler_tx = handler_tx.clone();
-      let (tx, rx) = channel::<Arc<Message>>();
-
-      mioco::spawn(move || -> io::Result<()> {
-        let player = Arc::new(Player::new(tx));
-        try!(add_player(player.clone(), &handler_tx));
-        if let Err(err) = controller::send(Arc::new(Message::new(MessageType::Welcome, "Welcome apprentice")), &player) {
-          return Err(io_err(&format!("Failed sending welcome {}", err)));
+  loop {
+    // a failed accept concerns that connection only, the listener goes on
+    let (mut conn, peer_addr) = match listener.accept().await {
+      Ok(accepted) => accepted,
+      Err(err) => {
+        error!("Failed accepting a connection : {}", err);
+        if matches!(err.raw_os_error(), Some(ENFILE) | Some(EMFILE)) {
+          sleep(Duration::from_millis(ACCEPT_RETRY_MS)).await;
+        }
+        continue;
+      }
+    };
+    let ip = peer_addr.ip();
+    if map_io_err(bans.lock())?.is_ip_banned(&ip) {
+      info!("Refused banned {}", ip);
+      if let Err(err) = write_message(&mut conn, &Message::new(MessageType::Disconnected, "Banned")).await {
+        debug!("Failed notifying banned {} : {}", ip, err);
+      }
+      continue;
+    }
+    let connection_guard = match connections::acquire(&connections, ip) {
+      Some(guard) => guard,
+      None => {
+        info!("Refused {}, server full", ip);
+        let retry_after = config.retry_after_secs.to_string();
+        if let Err(err) = write_message(&mut conn, &Message::new(MessageType::ServerFull, &retry_after)).await {
+          debug!("Failed notifying server full to {} : {}", ip, err);
         }
+        continue;
+      }
+    };
+    let handler_tx = handler_tx.clone();
+    let outbox = Outbox::new(config.outbox_high_water, config.outbox_capacity);
+    let rate_limiter = RateLimiter::new(&config.rate_limits, config.max_rate_violations);
+    let message_builder = MessageBuilder::new(config.max_message_length);
 
-        let mut message_builder = MessageBuilder::new();
-        let mut buf = [0u8; 1024];
-        loop {
-          select!(
-            r:conn => {
-              match try!(handle_read(&mut conn, &mut buf, message_builder, player.clone(), &handler_tx)) {
-                Some(mb) => message_builder = mb,
-                None => break
-              }
-            },
-            r:rx => {
-              try!(handle_write(&mut conn, &rx));
-            },
-          );
+    tokio::spawn(async move {
+      let _connection_guard = connection_guard;
+      let player = Arc::new(Player::new(outbox, Some(ip)));
+      if let Err(err) = serve(conn, player.clone(), &handler_tx, rate_limiter, message_builder).await {
+        debug!("Connection of {} failed : {}", player.id(), err);
+      }
+      // however the connection ended, unless the handler closed it and already released the player
+      if !player.is_closed() {
+        if let Err(err) = release_player(player.clone(), &handler_tx) {
+          error!("Failed releasing {} : {}", player.id(), err);
         }
-        debug!("leaving coroutine");
-        Ok(())
-      });
+      }
+      debug!("leaving connection task");
+    });
+  }
+}
+
+async fn serve(
+    conn : TcpStream,
+    player : Arc<Player>,
+    handler_tx : &UnboundedSender<HandlerParam>,
+    mut rate_limiter : RateLimiter,
+    mut message_builder : MessageBuilder) -> io::Result<()> {
+  add_player(player.clone(), handler_tx)?;
+  if let Err(err) = controller::send(Arc::new(Message::new(MessageType::Welcome, "Welcome apprentice")), &player) {
+    return Err(io_err(&format!("Failed sending welcome {}", err)));
+  }
 
+  let mut writer = Writer::new();
+  loop {
+    // only wait for the socket to be writable while a write is pending
+    let mut readable = false;
+    tokio::select! {
+      ready = conn.readable() => {
+        ready?;
+        readable = true
+      },
+      ready = conn.writable(), if writer.has_pending() => ready?,
+      _ = player.outbox.woken() => (),
+    }
+    if readable && !handle_read(&conn, &mut message_builder, player.clone(), handler_tx, &mut rate_limiter, &mut writer).await? {
+      break
+    }
+    if player.is_closed() {
+      queue_outbox(&player, &mut writer)?;
+      writer.flush_all(&conn, Duration::from_millis(CLOSE_FLUSH_TIMEOUT_MS)).await?;
+      info!("Closed {}", player.id());
+      break
+    }
+    if map_io_err(player.outbox.is_overflowed())? {
+      // the consumer is not reading, the notice is only written if there is room for it
+      queue_outbox(&player, &mut writer)?;
+      writer.flush(&conn)?;
+      warn!("Disconnected slow consumer {}", player.id());
+      break
     }
-  }).unwrap().unwrap();
+    handle_write(&conn, &player, &mut writer)?;
+  }
+  Ok(())
+}
+
+fn add_player(player : Arc<Player>, handler_tx : &UnboundedSender<HandlerParam>) -> io::Result<()> {
+  map_io_err(handler_tx.send((HandlerMessage::AddPlayer, player)))
+}
+
+fn release_player(player : Arc<Player>, handler_tx : &UnboundedSender<HandlerParam>) -> io::Result<()> {
+  map_io_err(handler_tx.send((HandlerMessage::ReleasePlayer, player)))
 }
 
-fn add_player(player : Arc<Player>,
-    handler_tx : &Mutex<Sender<HandlerParam>>) -> io::Result<()> {
-  let tx = try!(map_io_err(handler_tx.lock()));
-  map_io_err(tx.send((HandlerMessage::AddPlayer, player)))
+// writes the queued messages until the socket is full.
+// the outbox is only drained once the previous batch is written, so that its capacity still applies.
+fn handle_write(conn : &TcpStream, player : &Player, writer : &mut Writer) -> io::Result<()> {
+  loop {
+    if !writer.has_pending() && queue_outbox(player, writer)? == 0 {
+      return Ok(())
+    }
+    writer.flush(conn)?;
+    if writer.has_pending() {
+      return Ok(())
+    }
+  }
 }
 
-fn handle_write(conn : &mut MioAdapter<TcpStream>, rx: &Receiver<Arc<Message>>) -> io::Result<()> {
-  match rx.try_recv() {
-    Ok(msg) => {
-      let mut header = msg.header.to_string().into_bytes();
-      header.push(b'\n');
-      try!(conn.write_all(&header));
-      try!(conn.write_all(&msg.body));
-      try!(conn.flush());
-      debug!("Sent {:?}", msg.header);
-
-    },
-    Err(TryRecvError::Empty) => debug!("Write handle: empty event"),
-    Err(TryRecvError::Disconnected) => debug!("Write handle: disconnected event"),
+fn queue_outbox(player : &Player, writer : &mut Writer) -> io::Result<usize> {
+  let messages = map_io_err(player.outbox.drain())?;
+  let count = messages.len();
+  for msg in messages {
+    writer.queue(msg);
   }
+  Ok(count)
+}
+
+async fn write_message(conn : &mut TcpStream, msg : &Message) -> io::Result<()> {
+  let mut header = msg.header.to_string().into_bytes();
+  header.push(b'\n');
+  conn.write_all(&header).await?;
+  conn.write_all(&msg.body).await?;
+  conn.flush().await?;
+  debug!("Sent {:?}", msg.header);
   Ok(())
 }
 
-fn handle_read(
-    conn : &mut MioAdapter<TcpStream>,
-    mut buf : &mut [u8],
-    mut message_builder : MessageBuilder,
+// false once the connection is to be closed
+async fn handle_read(
+    conn : &TcpStream,
+    message_builder : &mut MessageBuilder,
     player : Arc<Player>,
-    handler_tx : &Mutex<Sender<HandlerParam>>) -> io::Result<Option<MessageBuilder>> {
-  let size_option = try!(conn.try_read(&mut buf));
+    handler_tx : &UnboundedSender<HandlerParam>,
+    rate_limiter : &mut RateLimiter,
+    writer : &mut Writer) -> io::Result<bool> {
+  let size_option = message_builder.read(|buf| match conn.try_read_buf(buf) {
+    Ok(size) => Ok(Some(size)),
+    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+    Err(e) => Err(e)
+  })?;
   if let Some(size) = size_option {
     if size == 0 {
-      info!("Left {}", player.id);
-      let tx = try!(map_io_err(handler_tx.lock()));
-      try!(map_io_err(tx.send((HandlerMessage::ReleasePlayer, player.clone()))));
-      return Ok(None);
+      info!("Left {}", player.id());
+      return Ok(false);
     }
-    let mut slice = &buf[0..size];
+    let mut exceeded = false;
     loop {
-      match message_builder.process(slice) {
-        Ok(processed) =>
-          match processed {
-            Right((message, offset)) => {
-              message_builder = MessageBuilder::new();
-              trace!("message found {}, remaining {}", offset, slice.len());
-              slice = try!(check_slice(&slice, offset, slice.len()));
-              let tx = try!(map_io_err(handler_tx.lock()));
-              try!(map_io_err(tx.send((HandlerMessage::ClientMessage(Arc::new(message)), player.clone()))));
+      match message_builder.next_message() {
+        Ok(Some(message)) => {
+          match rate_limiter.check(message.header.message_type) {
+            RateDecision::Allowed => {
+              map_io_err(handler_tx.send((HandlerMessage::ClientMessage(Arc::new(message)), player.clone())))?;
+            },
+            RateDecision::Throttled => {
+              debug!("Throttled msg type {} from {}", message.header.message_type, player.id());
+              let mut error = Message::new(MessageType::Error, "Rate limited");
+              error.header.answer_id = message.header.message_id;
+              writer.queue(Arc::new(error));
             },
-            Left(mb) => {
-              trace!("process no message continuing..");
-              message_builder = mb;
+            RateDecision::Exceeded => {
+              warn!("Disconnecting {} for exceeding rate limits", player.id());
+              writer.queue(Arc::new(Message::new(MessageType::Disconnected, "Rate limits exceeded")));
+              exceeded = true;
               break;
             }
-          },
-        Err(err) => {
-          error!("Failed processing buffer {}", err);
-          message_builder = MessageBuilder::new();
+          }
+        },
+        Ok(None) => {
+          trace!("process no message continuing..");
           break;
-        }
+        },
+        Err(err) => error!("Failed processing buffer {}", err)
       }
     }
+    if exceeded {
+      writer.flush_all(conn, Duration::from_millis(CLOSE_FLUSH_TIMEOUT_MS)).await?;
+      return Ok(false);
+    }
   }
-  Ok(Some(message_builder))
+  Ok(true)
 }
 
 fn init_logger() {
-  let format = |record: &LogRecord| {
-    format!("{} - {} {} {}",
-      time::strftime("%Y-%m-%d %H:%M:%S %Z", &time::now()).unwrap(),  
-      record.level(),
-      record.location().module_path(),
-      record.args())
-  };
-  let mut builder = LogBuilder::new();
+  let mut builder = Builder::new();
 
   builder
-    .format(format)
-    .filter(None, LogLevelFilter::Info);
+    .format(|buf, record| {
+      writeln!(buf, "{} - {} {} {}",
+        time::strftime("%Y-%m-%d %H:%M:%S %Z", &time::now()).unwrap(),
+        record.level(),
+        record.module_path().unwrap_or(""),
+        record.args())
+    })
+    .filter(None, LevelFilter::Info);
 
-  if env::var("RUST_LOG").is_ok() {
-    builder.parse(&env::var("RUST_LOG").unwrap());
+  if let Ok(filters) = env::var("RUST_LOG") {
+    builder.parse_filters(&filters);
   }
 
-  builder.init().unwrap();
-}
\ No newline at end of file
+  builder.init();
+}
@@ -1,91 +1,159 @@
-use either::*;
-use std::error::Error;
+use bytes::{Buf, BytesMut};
+use std::io;
 use std::str;
-use model::*;
+use crate::model::*;
+use crate::utils::*;
 
+// room made at the end of the buffer before each read
+const READ_SIZE : usize = 4096;
+// a header line is a few numbers, anything longer is garbage
+const MAX_HEADER_LENGTH : usize = 128;
+
+// reads the connection into one growable buffer, messages bodies are sliced out of it without copy
 pub struct MessageBuilder {
-  header_line : String,
-  header : Option<Header>,
-  body   : Vec<u8>
+  buf                : BytesMut,
+  header             : Option<Header>,
+  max_message_length : usize,
+  discard            : usize // body bytes of a refused message still to skip
 }
 
 impl MessageBuilder {
 
-  pub fn new() -> MessageBuilder {
+  pub fn new(max_message_length : usize) -> MessageBuilder {
     MessageBuilder {
-      header_line : String::new(),
-      header : None,
-      body   : Vec::new()
+      buf     : BytesMut::with_capacity(READ_SIZE),
+      header  : None,
+      max_message_length,
+      discard : 0
+    }
+  }
+
+  // read appends to the buffer, which has room for at least READ_SIZE more bytes, and returns how much it added, if any
+  pub fn read<F>(&mut self, read : F) -> io::Result<Option<usize>>
+      where F : FnOnce(&mut BytesMut) -> io::Result<Option<usize>> {
+    self.buf.reserve(READ_SIZE);
+    let result = read(&mut self.buf);
+    if let Ok(Some(size)) = result {
+      trace!("read {}, buffered {}", size, self.buf.len());
     }
+    result
   }
 
-  pub fn process(mut self, buf: &[u8]) -> Result<Either<MessageBuilder, (Message, usize)>, Box<Error>> {
-    trace!("process buf {}", buf.len());
-    let nb_read = match self.header {
-      Some(ref header) => MessageBuilder::process_body(&mut self.body, header, buf),
-      None => {
-        let line = MessageBuilder::get_line(buf);
-        let line_str = try!(str::from_utf8(line));
-        self.header_line.push_str(line_str);
-        if self.has_read_header() {
-          let header = try!(Header::parse(&self.header_line));
+  // the next complete message of the buffer, a malformed header line or a too long message is skipped
+  pub fn next_message(&mut self) -> BasicResult<Option<Message>> {
+    if self.discard > 0 {
+      let skipped = self.discard.min(self.buf.len());
+      self.buf.advance(skipped);
+      self.discard -= skipped;
+      if self.discard > 0 {
+        return Ok(None)
+      }
+    }
+    if self.header.is_none() {
+      match self.buf.iter().position(|&c| c == b'\n') {
+        Some(i) => {
+          let line = self.buf.split_to(i + 1);
+          let header = str::from_utf8(&line).map_err(From::from).and_then(Header::parse)?;
           trace!("Header read : {:?}", header);
-          let body_read = MessageBuilder::process_body(&mut self.body, &header, &buf[line.len() .. buf.len()]);
+          if header.message_length > self.max_message_length {
+            self.discard = header.message_length;
+            return Err(From::from(format!("Message of {} bytes too long", header.message_length)))
+          }
           self.header = Some(header);
-          line.len() + body_read
-        } else {
-          line.len()
+        },
+        None => {
+          if self.buf.len() > MAX_HEADER_LENGTH {
+            self.buf.clear();
+            return Err(From::from("Header line too long"))
+          }
+          return Ok(None)
         }
       }
+    }
+    let has_body = match self.header {
+      Some(ref header) => self.buf.len() >= header.message_length,
+      None => false
     };
-    trace!("process had read {}", nb_read);
-    if self.has_read_body() {
-      Ok(Right((Message { header : self.header.unwrap(), body : self.body }, nb_read)))
+    if has_body {
+      let header = self.header.take().unwrap();
+      let body = self.buf.split_to(header.message_length).freeze();
+      Ok(Some(Message { header, body }))
     } else {
-      Ok(Left(self))
+      Ok(None)
     }
   }
+}
 
-  fn process_body(body : &mut Vec<u8>, header : &Header, buf: &[u8]) -> usize {
-    trace!("read body {}/{}", body.len() , header.message_length);
-    if header.message_length == 0 {
-      0
-    } else {
-      let last_read = body.len();
-      let n = header.message_length - last_read;
-      let b = if buf.len() <n {
-        buf
-      } else {
-        &buf[0..n]
-      };
-      for c in b {
-        body.push(c.clone());
-      }
-      b.len()
-    }
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn feed(builder : &mut MessageBuilder, data : &str) {
+    let size = builder.read(|buf| {
+      buf.extend_from_slice(data.as_bytes());
+      Ok(Some(data.len()))
+    }).unwrap();
+    assert_eq!(size, Some(data.len()));
   }
 
-  fn get_line(buf : &[u8]) -> & [u8] {
-    trace!("get line");
-    let mut i = 0;
-    if buf.len() == 0 {
-      buf
-    } else {
-      while i < buf.len() -1 && buf[i] != b'\n' {
-        i+=1
-      }
-      &buf[0..i+1]
-    }
+  fn next(builder : &mut MessageBuilder) -> Option<(MessageType::Value, String)> {
+    builder.next_message().unwrap()
+      .map(|msg| (msg.header.message_type, msg.body_as_str().unwrap().to_string()))
   }
 
-  fn has_read_header(&self) -> bool {
-    self.header_line.len() > 3 && self.header_line.as_bytes()[self.header_line.len() - 1] == b'\n'
+  #[test]
+  fn header_split_across_reads() {
+    let mut builder = MessageBuilder::new(1024);
+    feed(&mut builder, "9;5;");
+    assert_eq!(next(&mut builder), None);
+    feed(&mut builder, "1;0\nhello");
+    assert_eq!(next(&mut builder), Some((9, "hello".to_string())));
+    assert_eq!(next(&mut builder), None);
   }
-  
-  fn has_read_body(&self) -> bool {
-    match self.header {
-      Some(ref header) => header.message_length == self.body.len(),
-      _ => false
-    }
+
+  #[test]
+  fn body_split_across_reads() {
+    let mut builder = MessageBuilder::new(1024);
+    feed(&mut builder, "9;5;1;0\nhe");
+    assert_eq!(next(&mut builder), None);
+    feed(&mut builder, "llo");
+    assert_eq!(next(&mut builder), Some((9, "hello".to_string())));
+  }
+
+  #[test]
+  fn several_messages_in_one_read() {
+    let mut builder = MessageBuilder::new(1024);
+    feed(&mut builder, "9;2;1;0\nhi1;3;2;0\nbob11;0;3;0\n9;1");
+    assert_eq!(next(&mut builder), Some((9, "hi".to_string())));
+    assert_eq!(next(&mut builder), Some((1, "bob".to_string())));
+    assert_eq!(next(&mut builder), Some((11, "".to_string())));
+    assert_eq!(next(&mut builder), None);
+  }
+
+  #[test]
+  fn malformed_header_is_skipped() {
+    let mut builder = MessageBuilder::new(1024);
+    feed(&mut builder, "garbage\n9;2;1;0\nhi");
+    assert!(builder.next_message().is_err());
+    assert_eq!(next(&mut builder), Some((9, "hi".to_string())));
+  }
+
+  #[test]
+  fn too_long_header_line_is_dropped() {
+    let mut builder = MessageBuilder::new(1024);
+    feed(&mut builder, &"9".repeat(MAX_HEADER_LENGTH + 1));
+    assert!(builder.next_message().is_err());
+    feed(&mut builder, "9;2;1;0\nhi");
+    assert_eq!(next(&mut builder), Some((9, "hi".to_string())));
+  }
+
+  #[test]
+  fn too_long_message_is_skipped() {
+    let mut builder = MessageBuilder::new(4);
+    feed(&mut builder, "9;10;1;0\n01234");
+    assert!(builder.next_message().is_err());
+    assert_eq!(next(&mut builder), None);
+    feed(&mut builder, "567899;2;2;0\nhi");
+    assert_eq!(next(&mut builder), Some((9, "hi".to_string())));
   }
 }
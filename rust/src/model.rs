@@ -1,67 +1,235 @@
+use std::collections::HashSet;
 use std::error::Error;
 use std::fmt::{self, Debug, Formatter, Display};
+use std::net::IpAddr;
 use std::str::{self, Utf8Error};
-use std::sync::Arc;
-use mioco::sync::{Mutex, RwLock};
-use mioco::sync::mpsc::Sender;
-use utils::*;
-use rand;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use base64::decode;
+use bytes::Bytes;
+use crate::outbox::Outbox;
+use crate::utils::*;
 
 pub type Id = u32;
 
+pub const DEFAULT_LOBBY : &str = "main";
+pub const MAX_LOBBY_NAME_LENGTH : usize = 32;
+pub const MAX_LIST_PAGE : usize = 100;
+// lobby list versions start at 0 and only grow, so this one never matches
+pub const UNKNOWN_LIST_VERSION : u64 = u64::MAX;
+
 pub struct Player {
-  pub id     : Id,
-  pub tx     : Mutex<Sender<Arc<Message>>>,
+  id         : AtomicU32, // changes when logging in an account
+  closed     : AtomicBool, // set by the handler, the connection is closed once its queue is written
+  pub addr   : Option<IpAddr>,
+  pub outbox : Outbox,
   pub state  : RwLock<Arc<PlayerState>> // this lock is quite uselsss as it is never read elsewhere than handler
 }
 
 impl Player {
 
-  pub fn new(tx : Sender<Arc<Message>>) -> Player {
+  pub fn new(outbox : Outbox, addr : Option<IpAddr>) -> Player {
     Player {
-      id    : rand::random(),
-      tx    : Mutex::new(tx),
+      id    : AtomicU32::new(rand::random()),
+      closed : AtomicBool::new(false),
+      addr,
+      outbox,
       state : RwLock::new(Arc::new(
         PlayerState {
           status : PlayerStatus::OnHold,
-          name   : String::new()
+          name   : String::new(),
+          rtt    : 0,
+          lobby  : DEFAULT_LOBBY.to_string(),
+          blocked : HashSet::new(),
+          authenticated : false,
+          admin  : false,
+          admin_failures : 0,
+          observer_key_failures : 0,
+          list_version : None
         }))
     }
   }
 
+  pub fn id(&self) -> Id {
+    self.id.load(Ordering::SeqCst)
+  }
+
+  // only the handler should call it, after removing the old id from the state
+  pub fn set_id(&self, id : Id) {
+    self.id.store(id, Ordering::SeqCst)
+  }
+
+  pub fn close(&self) {
+    self.closed.store(true, Ordering::SeqCst)
+  }
+
+  pub fn is_closed(&self) -> bool {
+    self.closed.load(Ordering::SeqCst)
+  }
+
+  pub fn authenticate(&self, name : String) -> BasicResult<()> {
+    self.update_state(move |state| {
+      PlayerState {
+        name,
+        authenticated : true,
+        .. state.clone()
+      }
+    })
+  }
+
+  pub fn set_admin(&self) -> BasicResult<()> {
+    self.update_state(move |state| {
+      PlayerState {
+        admin : true,
+        .. state.clone()
+      }
+    })
+  }
+
+  // returns the failures of the connection so far
+  pub fn fail_admin_login(&self) -> BasicResult<u32> {
+    self.update_state(|state| {
+      PlayerState {
+        admin_failures : state.admin_failures + 1,
+        .. state.clone()
+      }
+    })?;
+    let state = box_err(self.state.read())?;
+    Ok(state.admin_failures)
+  }
+
+  // returns the failures of the connection so far
+  pub fn fail_observer_key(&self) -> BasicResult<u32> {
+    self.update_state(|state| {
+      PlayerState {
+        observer_key_failures : state.observer_key_failures + 1,
+        .. state.clone()
+      }
+    })?;
+    let state = box_err(self.state.read())?;
+    Ok(state.observer_key_failures)
+  }
+
+  pub fn is_admin(&self) -> BasicResult<bool> {
+    let state = box_err(self.state.read())?;
+    Ok(state.admin)
+  }
+
+  pub fn is_authenticated(&self) -> BasicResult<bool> {
+    let state = box_err(self.state.read())?;
+    Ok(state.authenticated)
+  }
+
   pub fn set_name(&self, name : String) -> BasicResult<()> {
     self.update_state(move |state| {
       PlayerState {
-        name : name,
-        status: state.status.clone()
+        name,
+        .. state.clone()
+      }
+    })
+  }
+
+  // the version of the new lobby list is unrelated, the player takes a snapshot on the next broadcast
+  pub fn set_lobby(&self, lobby : String) -> BasicResult<()> {
+    self.update_state(move |state| {
+      PlayerState {
+        lobby,
+        list_version : state.list_version.map(|_| UNKNOWN_LIST_VERSION),
+        .. state.clone()
       }
     })
   }
 
+  pub fn set_list_version(&self, list_version : Option<u64>) -> BasicResult<()> {
+    self.update_state(move |state| {
+      PlayerState {
+        list_version,
+        .. state.clone()
+      }
+    })
+  }
+
+  // changed in place, the state is only copied when shared
+  pub fn set_blocked(&self, id : Id, blocked : bool) -> BasicResult<()> {
+    let mut st = box_err(self.state.write())?;
+    let state = Arc::make_mut(&mut st);
+    if blocked {
+      state.blocked.insert(id);
+    } else {
+      state.blocked.remove(&id);
+    }
+    Ok(())
+  }
+
+  pub fn blocked_count(&self) -> BasicResult<usize> {
+    let state = box_err(self.state.read())?;
+    Ok(state.blocked.len())
+  }
+
   pub fn set_status(&self, status : PlayerStatus) -> BasicResult<()> {
     self.update_state(move |state| {
       PlayerState {
-        name : state.name.to_owned(),
-        status: status
+        status,
+        .. state.clone()
       }
     })
   }
 
-  pub fn is_on_hold(&self) -> BasicResult<bool> {
-    let state = try!(box_err(self.state.read()));
-    Ok(match state.status {
-      PlayerStatus::OnHold => true,
-      _ => false
+  // smoothed like tcp srtt (rfc 6298, alpha = 1/8)
+  pub fn update_rtt(&self, sample : u32) -> BasicResult<()> {
+    self.update_state(move |state| {
+      let rtt = if state.rtt == 0 {
+        sample
+      } else {
+        ((u64::from(state.rtt) * 7 + u64::from(sample)) / 8) as u32
+      };
+      PlayerState {
+        rtt,
+        .. state.clone()
+      }
     })
   }
 
+  pub fn is_on_hold(&self) -> BasicResult<bool> {
+    let state = box_err(self.state.read())?;
+    Ok(matches!(state.status, PlayerStatus::OnHold))
+  }
+
   pub fn is_on_hold_unsafe(&self) -> bool {
     self.is_on_hold().unwrap_or(false)
   }
 
+  pub fn name(&self) -> BasicResult<String> {
+    let state = box_err(self.state.read())?;
+    Ok(state.name.clone())
+  }
+
+  pub fn lobby(&self) -> BasicResult<String> {
+    let state = box_err(self.state.read())?;
+    Ok(state.lobby.clone())
+  }
+
+  pub fn list_version(&self) -> BasicResult<Option<u64>> {
+    let state = box_err(self.state.read())?;
+    Ok(state.list_version)
+  }
+
+  pub fn blocked(&self) -> BasicResult<HashSet<Id>> {
+    let state = box_err(self.state.read())?;
+    Ok(state.blocked.clone())
+  }
+
+  pub fn has_blocked_unsafe(&self, id : Id) -> bool {
+    self.state.read().map(|state| state.blocked.contains(&id)).unwrap_or(false)
+  }
+
+  pub fn is_in_lobby_unsafe(&self, lobby : &str) -> bool {
+    self.state.read().map(|state| state.lobby == lobby).unwrap_or(false)
+  }
+
   fn update_state<F>(&self, update : F) -> BasicResult<()> 
     where F : FnOnce(&PlayerState) -> PlayerState {
-    let mut st = try!(box_err(self.state.write()));
+    let mut st = box_err(self.state.write())?;
     *Arc::make_mut(&mut st) = update(&st);
     Ok(())
   }
@@ -69,7 +237,7 @@ impl Player {
 
 impl Debug for Player {
   fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
-    let s = format!("Player[{}, {:?}]", self.id, self.state);
+    let s = format!("Player[{}, {:?}]", self.id(), self.state);
     Display::fmt(&s, f)
   }
 }
@@ -77,51 +245,153 @@ impl Debug for Player {
 #[derive(Clone, Debug)]
 pub struct PlayerState {
   pub status : PlayerStatus,
-  pub name   : String
+  pub name   : String,
+  pub rtt    : u32, // smoothed round trip time in ms, 0 until measured
+  pub lobby  : String,
+  pub blocked : HashSet<Id>,
+  pub authenticated : bool, // identity is from an account and not from the name message
+  pub admin  : bool,
+  pub admin_failures : u32, // failed admin logins of the connection
+  pub observer_key_failures : u32, // wrong observer keys given by the connection
+  pub list_version : Option<u64> // last lobby list version received when taking deltas, none for whole lists
 }
 
 #[derive(Clone, Debug)]
 pub enum PlayerStatus {
   OnHold,
-  Duelling(Duel)
+  Duelling // the opponent is in the duel table of the state
 }
 
+// players are referenced by id so that duelling players don't keep each other alive
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Duel {
-  pub player1 : Arc<Player>,
-  pub player2 : Arc<Player>
+  pub player1 : Id,
+  pub player2 : Id
 }
 
-impl Clone for Duel {
-  fn clone(&self) -> Duel {
-    Duel { 
-      player1 : self.player1.clone(),
-      player2 : self.player2.clone(),
-    } 
+impl Duel {
+  
+  pub fn other_player(&self, id : Id) -> Id {
+    if self.player1 == id {
+      self.player2
+    } else {
+      self.player1
+    }
   }
 }
 
-impl Debug for Duel {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
-    let s = format!("[{} vs {}]", self.player1.id, self.player2.id);
-    Display::fmt(&s, f)
-  }
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListSort {
+  Id,
+  Name,
+  Rtt
 }
 
-impl Duel {
-  
-  pub fn other_player(&self, id : Id) -> Arc<Player> {
-    if self.player1.id == id {
-      self.player2.clone()
-    } else {
-      self.player1.clone()
+// a ListPlayers request body, field:value joined by ';' with the strings in base64 :
+// status:0|1, prefix:name, lobby:name, sort:[-]id|name|rtt, offset:n or page:n, limit:n
+#[derive(Debug)]
+pub struct ListQuery {
+  pub on_hold     : Option<bool>,
+  pub name_prefix : Option<String>,
+  pub lobby       : Option<String>, // the lobby of the player when none
+  pub sort        : ListSort,
+  pub descending  : bool,
+  pub offset      : usize,
+  pub limit       : usize
+}
+
+impl ListQuery {
+
+  pub fn parse(s : &str) -> BasicResult<ListQuery> {
+    let mut query = ListQuery {
+      on_hold     : None,
+      name_prefix : None,
+      lobby       : None,
+      sort        : ListSort::Id,
+      descending  : false,
+      offset      : 0,
+      limit       : MAX_LIST_PAGE
+    };
+    let mut page = None;
+    for field in s.split(';').filter(|f| !f.is_empty()) {
+      let (key, value) = match field.find(':') {
+        Some(i) => (&field[..i], &field[i+1..]),
+        None => return Err(From::from(format!("Invalid list filter {}", field)))
+      };
+      match key {
+        "status" => query.on_hold = Some(match value {
+          "0" => true,
+          "1" => false,
+          _ => return Err(From::from(format!("Invalid status {}", value)))
+        }),
+        "prefix" => query.name_prefix = Some(String::from_utf8(decode(value)?)?),
+        "lobby" => query.lobby = Some(String::from_utf8(decode(value)?)?),
+        "sort" => {
+          query.descending = value.starts_with('-');
+          query.sort = match value.trim_start_matches('-') {
+            "id" => ListSort::Id,
+            "name" => ListSort::Name,
+            "rtt" => ListSort::Rtt,
+            _ => return Err(From::from(format!("Invalid sort {}", value)))
+          };
+        },
+        "offset" => query.offset = value.parse()?,
+        "page" => page = Some(value.parse::<usize>()?),
+        "limit" => query.limit = value.parse::<usize>()?.min(MAX_LIST_PAGE),
+        _ => return Err(From::from(format!("Unknown list filter {}", key)))
+      }
+    }
+    if let Some(page) = page {
+      query.offset = page.saturating_mul(query.limit);
     }
+    Ok(query)
+  }
+}
+
+// a change of a lobby list, numbered so that clients notice when they missed one
+#[derive(Debug)]
+pub struct ListDelta {
+  pub version : u64,
+  pub id      : Id,
+  pub change  : ListChange
+}
+
+#[derive(Debug)]
+pub enum ListChange {
+  Joined(String), // the player string
+  Updated(String),
+  Left
+}
+
+// a duel waiting for the holder of its code
+pub struct PrivateRoom {
+  pub creator  : Id,
+  pub password : Option<String>,
+  pub hidden   : bool // the creator is left out of the player lists meanwhile
+}
+
+impl Debug for PrivateRoom {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+    f.debug_struct("PrivateRoom")
+      .field("creator", &self.creator)
+      .field("password", &self.password.as_ref().map(|_| REDACTED))
+      .field("hidden", &self.hidden)
+      .finish()
   }
 }
 
+// an observer receiving the delayed traffic of the duel of player_id
+#[derive(Debug, PartialEq)]
+pub struct Subscription {
+  pub observer_id : Id,
+  pub player_id   : Id
+}
+
 #[derive(Debug)]
-pub struct Request {
-  pub src_id  : Id,
-  pub dest_id : Id
+pub struct DelayedMessage {
+  pub release_at  : u64,
+  pub observer_id : Id,
+  pub msg         : Arc<Message>
 }
 
 #[allow(non_snake_case)]
@@ -143,9 +413,94 @@ pub mod MessageType {
   pub const ExitDuel     : Value = 6;
   #[allow(non_upper_case_globals)]
   pub const ListPlayers  : Value = 7;
+  #[allow(non_upper_case_globals)]
+  pub const Ping         : Value = 8;
+  #[allow(non_upper_case_globals)]
+  pub const LobbyChat    : Value = 9;
+  #[allow(non_upper_case_globals)]
+  pub const Error        : Value = 10;
+  #[allow(non_upper_case_globals)]
+  pub const Whisper      : Value = 11;
+  #[allow(non_upper_case_globals)]
+  pub const JoinLobby    : Value = 12;
+  #[allow(non_upper_case_globals)]
+  pub const ListLobbies  : Value = 13;
+  #[allow(non_upper_case_globals)]
+  pub const WatchDuel    : Value = 14;
+  #[allow(non_upper_case_globals)]
+  pub const BroadcastFeed: Value = 15;
+  #[allow(non_upper_case_globals)]
+  pub const Block        : Value = 16;
+  #[allow(non_upper_case_globals)]
+  pub const Unblock      : Value = 17;
+  #[allow(non_upper_case_globals)]
+  pub const FriendRequest: Value = 18;
+  #[allow(non_upper_case_globals)]
+  pub const RemoveFriend : Value = 19;
+  #[allow(non_upper_case_globals)]
+  pub const ListFriends  : Value = 20;
+  #[allow(non_upper_case_globals)]
+  pub const FriendStatus : Value = 21;
+  #[allow(non_upper_case_globals)]
+  pub const Register     : Value = 22;
+  #[allow(non_upper_case_globals)]
+  pub const Login        : Value = 23;
+  #[allow(non_upper_case_globals)]
+  pub const Auth         : Value = 24;
+  #[allow(non_upper_case_globals)]
+  pub const SessionReplaced : Value = 25;
+  #[allow(non_upper_case_globals)]
+  pub const ResumeDuel   : Value = 26;
+  #[allow(non_upper_case_globals)]
+  pub const AdminLogin   : Value = 27;
+  #[allow(non_upper_case_globals)]
+  pub const Kick         : Value = 28;
+  #[allow(non_upper_case_globals)]
+  pub const Ban          : Value = 29;
+  #[allow(non_upper_case_globals)]
+  pub const Announce     : Value = 30;
+  #[allow(non_upper_case_globals)]
+  pub const EndDuel      : Value = 31;
+  #[allow(non_upper_case_globals)]
+  pub const Disconnected : Value = 32;
+  #[allow(non_upper_case_globals)]
+  pub const Unban        : Value = 33;
+  #[allow(non_upper_case_globals)]
+  pub const ListBans     : Value = 34;
+  #[allow(non_upper_case_globals)]
+  pub const ServerFull   : Value = 35;
+  #[allow(non_upper_case_globals)]
+  pub const ListDeltas   : Value = 36;
+  #[allow(non_upper_case_globals)]
+  pub const PlayerJoined : Value = 37;
+  #[allow(non_upper_case_globals)]
+  pub const PlayerLeft   : Value = 38;
+  #[allow(non_upper_case_globals)]
+  pub const PlayerUpdated: Value = 39;
+  #[allow(non_upper_case_globals)]
+  pub const ListSnapshot : Value = 40;
+  #[allow(non_upper_case_globals)]
+  pub const RequestDuelByName : Value = 41;
+  #[allow(non_upper_case_globals)]
+  pub const FindPlayer   : Value = 42;
+  #[allow(non_upper_case_globals)]
+  pub const CreatePrivateDuel : Value = 43;
+  #[allow(non_upper_case_globals)]
+  pub const JoinByCode   : Value = 44;
 
   #[allow(non_upper_case_globals)]
   pub const Dump         : Value = 100;
+
+  #[allow(non_upper_case_globals)]
+  pub fn is_privileged(msg_type : Value) -> bool {
+    matches!(msg_type, Kick | Ban | Unban | ListBans | Announce | EndDuel | Dump)
+  }
+
+  // friendships are keyed by name, only an account holds its name for good
+  #[allow(non_upper_case_globals)]
+  pub fn needs_account(msg_type : Value) -> bool {
+    matches!(msg_type, FriendRequest | RemoveFriend | ListFriends)
+  }
 }
 
 #[derive(Debug)]
@@ -156,20 +511,25 @@ pub struct Header {
   pub answer_id      : i64
 }
 
-impl Header  {
-
-  pub fn to_string(&self) -> String {
-    format!("{};{};{};{}", self.message_type, self.message_length, self.message_id, self.answer_id)
+impl Display for Header {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+    write!(f, "{};{};{};{}", self.message_type, self.message_length, self.message_id, self.answer_id)
   }
+}
 
-  pub fn parse(s : &str) -> Result<Header, Box<Error>> {
+impl Header  {
+
+  pub fn parse(s : &str) -> Result<Header, Box<dyn Error>> {
     let v : Vec<&str> = s.trim_matches('\n').split(";").collect();
+    if v.len() != 4 {
+      return Err(From::from(format!("Invalid header {}", s.trim_matches('\n'))))
+    }
     Ok(
       Header {
-        message_type   : try!(v[0].parse()),
-        message_length : try!(v[1].parse()),
-        message_id     : try!(v[2].parse()),
-        answer_id      : try!(v[3].parse())
+        message_type   : v[0].parse()?,
+        message_length : v[1].parse()?,
+        message_id     : v[2].parse()?,
+        answer_id      : v[3].parse()?
       })
   }
 }
@@ -177,13 +537,16 @@ impl Header  {
 #[derive(Debug)]
 pub struct Message {
   pub header : Header,
-  pub body   : Vec<u8>
+  pub body   : Bytes // shared, a proxied body is never copied
 }
 
 impl Message  {
 
   pub fn new(msg_type : MessageType::Value, body : &str) -> Message {
-    let msg_body = body.as_bytes().to_vec();
+    Message::with_body(msg_type, body.as_bytes().to_vec())
+  }
+
+  pub fn with_body(msg_type : MessageType::Value, msg_body : Vec<u8>) -> Message {
     Message{
       header : Header {
         message_type : msg_type,
@@ -191,7 +554,7 @@ impl Message  {
         message_id : 0,
         answer_id : 0
       },
-      body : msg_body
+      body : Bytes::from(msg_body)
     }
   }
 
@@ -0,0 +1,127 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use crate::model::*;
+use crate::utils::*;
+
+// bounded queue of the messages to write to a player, the writer is woken up through the notify
+pub struct Outbox {
+  queue      : Mutex<OutboxQueue>,
+  wakeup     : Notify, // pushes between two wakeups are coalesced into one
+  high_water : usize, // above it, pending list updates are replaced by the newest one
+  capacity   : usize  // above it, the queue is dropped and the player disconnected
+}
+
+struct OutboxQueue {
+  messages   : VecDeque<Arc<Message>>,
+  overflowed : bool
+}
+
+impl Outbox {
+
+  pub fn new(high_water : usize, capacity : usize) -> Outbox {
+    Outbox {
+      queue : Mutex::new(OutboxQueue {
+        messages   : VecDeque::new(),
+        overflowed : false
+      }),
+      wakeup     : Notify::new(),
+      high_water,
+      capacity
+    }
+  }
+
+  pub fn push(&self, msg : Arc<Message>) -> BasicResult<()> {
+    let mut queue = box_err(self.queue.lock())?;
+    if queue.overflowed {
+      return Err(From::from("Outbound queue overflowed"))
+    }
+    if queue.messages.len() >= self.high_water && is_list_update(&msg) {
+      queue.messages.retain(|m| !is_list_update(m));
+    }
+    if queue.messages.len() >= self.capacity {
+      queue.overflowed = true;
+      queue.messages.clear();
+      queue.messages.push_back(Arc::new(Message::new(MessageType::Disconnected, "Slow consumer")));
+      self.wake();
+      return Err(From::from("Outbound queue overflowed"))
+    }
+    queue.messages.push_back(msg);
+    self.wake();
+    Ok(())
+  }
+
+  // takes every queued message at once
+  pub fn drain(&self) -> BasicResult<Vec<Arc<Message>>> {
+    let mut queue = box_err(self.queue.lock())?;
+    Ok(queue.messages.drain(..).collect())
+  }
+
+  pub fn is_overflowed(&self) -> BasicResult<bool> {
+    let queue = box_err(self.queue.lock())?;
+    Ok(queue.overflowed)
+  }
+
+  pub fn wake(&self) {
+    self.wakeup.notify_one()
+  }
+
+  // resolves once something was pushed since the last wakeup
+  pub async fn woken(&self) {
+    self.wakeup.notified().await
+  }
+}
+
+// list broadcasts are superseded by the next one, unlike answers to ListPlayers
+fn is_list_update(msg : &Message) -> bool {
+  msg.header.message_type == MessageType::ListPlayers && msg.header.answer_id == 0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn msg(msg_type : MessageType::Value, body : &str) -> Arc<Message> {
+    Arc::new(Message::new(msg_type, body))
+  }
+
+  fn bodies(outbox : &Outbox) -> Vec<String> {
+    outbox.drain().unwrap().iter().map(|m| m.body_as_str().unwrap().to_string()).collect()
+  }
+
+  #[test]
+  fn list_updates_are_kept_below_high_water() {
+    let outbox = Outbox::new(3, 10);
+    outbox.push(msg(MessageType::ListPlayers, "a")).unwrap();
+    outbox.push(msg(MessageType::ListPlayers, "b")).unwrap();
+    assert_eq!(bodies(&outbox), vec!["a", "b"]);
+  }
+
+  #[test]
+  fn list_updates_are_coalesced_above_high_water() {
+    let outbox = Outbox::new(3, 10);
+    outbox.push(msg(MessageType::ListPlayers, "a")).unwrap();
+    outbox.push(msg(MessageType::LobbyChat, "hi")).unwrap();
+    let mut answer = Message::new(MessageType::ListPlayers, "answer");
+    answer.header.answer_id = 4;
+    outbox.push(Arc::new(answer)).unwrap();
+    outbox.push(msg(MessageType::ListPlayers, "b")).unwrap();
+    // answers and other messages survive, only the stale broadcast goes
+    assert_eq!(bodies(&outbox), vec!["hi", "answer", "b"]);
+  }
+
+  #[test]
+  fn overflow_leaves_only_the_disconnect_notice() {
+    let outbox = Outbox::new(10, 2);
+    outbox.push(msg(MessageType::LobbyChat, "1")).unwrap();
+    outbox.push(msg(MessageType::LobbyChat, "2")).unwrap();
+    assert!(!outbox.is_overflowed().unwrap());
+    assert!(outbox.push(msg(MessageType::LobbyChat, "3")).is_err());
+    assert!(outbox.is_overflowed().unwrap());
+    let drained = outbox.drain().unwrap();
+    assert_eq!(drained.len(), 1);
+    assert_eq!(drained[0].header.message_type, MessageType::Disconnected);
+    assert!(outbox.push(msg(MessageType::LobbyChat, "4")).is_err());
+    assert!(outbox.drain().unwrap().is_empty());
+  }
+}
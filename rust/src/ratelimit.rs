@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::time::Instant;
+use crate::model::MessageType;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+  pub burst : f64,
+  pub rate  : f64 // tokens per second
+}
+
+pub enum RateDecision {
+  Allowed,
+  Throttled,
+  Exceeded // too many violations, the client should be disconnected
+}
+
+struct Bucket {
+  limit  : RateLimit,
+  tokens : f64,
+  last   : Instant
+}
+
+impl Bucket {
+
+  fn take(&mut self, now : Instant) -> bool {
+    let elapsed = now.duration_since(self.last);
+    let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    self.tokens = (self.tokens + elapsed_secs * self.limit.rate).min(self.limit.burst);
+    self.last = now;
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+}
+
+// one per connection, violations are forgiven at one per second
+pub struct RateLimiter {
+  buckets        : HashMap<MessageType::Value, Bucket>,
+  violations     : Bucket,
+  max_violations : f64
+}
+
+impl RateLimiter {
+
+  pub fn new(limits : &HashMap<MessageType::Value, RateLimit>, max_violations : f64) -> RateLimiter {
+    let now = Instant::now();
+    RateLimiter {
+      buckets : limits.iter()
+        .map(|(&msg_type, &limit)| (msg_type, Bucket { limit, tokens : limit.burst, last : now }))
+        .collect(),
+      violations : Bucket {
+        limit  : RateLimit { burst : max_violations, rate : 1.0 },
+        tokens : max_violations,
+        last   : now
+      },
+      max_violations
+    }
+  }
+
+  pub fn check(&mut self, msg_type : MessageType::Value) -> RateDecision {
+    let now = Instant::now();
+    match self.buckets.get_mut(&msg_type) {
+      Some(bucket) => {
+        if bucket.take(now) {
+          RateDecision::Allowed
+        } else if self.max_violations > 0.0 && !self.violations.take(now) {
+          RateDecision::Exceeded
+        } else {
+          RateDecision::Throttled
+        }
+      },
+      None => RateDecision::Allowed
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // a rate slow enough that no token comes back during a test
+  fn limiter(burst : f64, max_violations : f64) -> RateLimiter {
+    let mut limits = HashMap::new();
+    limits.insert(MessageType::LobbyChat, RateLimit { burst, rate : 0.001 });
+    RateLimiter::new(&limits, max_violations)
+  }
+
+  #[test]
+  fn burst_is_allowed_then_throttled_then_exceeded() {
+    let mut limiter = limiter(3.0, 2.0);
+    for _ in 0..3 {
+      assert!(matches!(limiter.check(MessageType::LobbyChat), RateDecision::Allowed));
+    }
+    for _ in 0..2 {
+      assert!(matches!(limiter.check(MessageType::LobbyChat), RateDecision::Throttled));
+    }
+    assert!(matches!(limiter.check(MessageType::LobbyChat), RateDecision::Exceeded));
+  }
+
+  #[test]
+  fn unlimited_types_are_always_allowed() {
+    let mut limiter = limiter(1.0, 1.0);
+    for _ in 0..10 {
+      assert!(matches!(limiter.check(MessageType::Name), RateDecision::Allowed));
+    }
+  }
+
+  #[test]
+  fn no_max_violations_only_throttles() {
+    let mut limiter = limiter(1.0, 0.0);
+    assert!(matches!(limiter.check(MessageType::LobbyChat), RateDecision::Allowed));
+    for _ in 0..10 {
+      assert!(matches!(limiter.check(MessageType::LobbyChat), RateDecision::Throttled));
+    }
+  }
+}
@@ -0,0 +1,146 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use crate::model::*;
+use crate::utils::*;
+
+// players indexed by id and name, partitioned by status so that the handler never scans every player.
+// status and name changes must go through set_status and set_name to keep the indexes in sync.
+#[derive(Debug)]
+pub struct PlayerRegistry {
+  by_id    : HashMap<Id, Arc<Player>>,
+  by_name  : HashMap<String, Id>, // a name is held by one live player, see claim_identity
+  on_hold  : HashSet<Id>,
+  duelling : HashSet<Id>
+}
+
+impl PlayerRegistry {
+
+  pub fn new() -> PlayerRegistry {
+    PlayerRegistry {
+      by_id    : HashMap::new(),
+      by_name  : HashMap::new(),
+      on_hold  : HashSet::new(),
+      duelling : HashSet::new()
+    }
+  }
+
+  pub fn add(&mut self, player : Arc<Player>) {
+    let id = player.id();
+    if player.is_on_hold_unsafe() {
+      self.on_hold.insert(id);
+    } else {
+      self.duelling.insert(id);
+    }
+    self.index_name(&player, id);
+    if let Some(previous) = self.by_id.insert(id, player) {
+      warn!("Replaced registered player {}", previous.id());
+    }
+  }
+
+  // only removes this very session, not another one that took its id
+  pub fn remove(&mut self, player : &Player) -> Option<Arc<Player>> {
+    let id = player.id();
+    match self.by_id.get(&id) {
+      Some(p) if std::ptr::eq(p.as_ref(), player) => (),
+      _ => return None
+    }
+    self.on_hold.remove(&id);
+    self.duelling.remove(&id);
+    self.unindex_name(player, id);
+    self.by_id.remove(&id)
+  }
+
+  pub fn get(&self, id : Id) -> Option<&Arc<Player>> {
+    self.by_id.get(&id)
+  }
+
+  pub fn get_on_hold(&self, id : Id) -> Option<&Arc<Player>> {
+    if self.on_hold.contains(&id) {
+      self.by_id.get(&id)
+    } else {
+      None
+    }
+  }
+
+  pub fn contains(&self, player : &Player) -> bool {
+    self.by_id.get(&player.id()).map(|p| std::ptr::eq(p.as_ref(), player)).unwrap_or(false)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Arc<Player>> {
+    self.by_id.values()
+  }
+
+  pub fn named(&self, name : &str) -> Option<&Arc<Player>> {
+    self.by_name.get(name).and_then(|id| self.by_id.get(id))
+  }
+
+  pub fn on_hold(&self) -> impl Iterator<Item = &Arc<Player>> {
+    self.on_hold.iter().filter_map(move |id| self.by_id.get(id))
+  }
+
+  pub fn set_status(&mut self, player : &Player, status : PlayerStatus) -> BasicResult<()> {
+    let on_hold = matches!(status, PlayerStatus::OnHold);
+    player.set_status(status)?;
+    if self.contains(player) {
+      let id = player.id();
+      if on_hold {
+        self.duelling.remove(&id);
+        self.on_hold.insert(id);
+      } else {
+        self.on_hold.remove(&id);
+        self.duelling.insert(id);
+      }
+    }
+    Ok(())
+  }
+
+  pub fn set_name(&mut self, player : &Player, name : String) -> BasicResult<()> {
+    self.rename(player, || player.set_name(name))
+  }
+
+  pub fn authenticate(&mut self, player : &Player, name : String) -> BasicResult<()> {
+    self.rename(player, || player.authenticate(name))
+  }
+
+  fn rename<F>(&mut self, player : &Player, update : F) -> BasicResult<()>
+      where F : FnOnce() -> BasicResult<()> {
+    let registered = self.contains(player);
+    if registered {
+      self.unindex_name(player, player.id());
+    }
+    let result = update();
+    if registered {
+      self.index_name(player, player.id());
+    }
+    result
+  }
+
+  fn index_name(&mut self, player : &Player, id : Id) {
+    if let Ok(name) = player.name() {
+      if !name.is_empty() {
+        if let Some(previous) = self.by_name.insert(name, id) {
+          warn!("Name of {} taken over by {}", previous, id);
+        }
+      }
+    }
+  }
+
+  fn unindex_name(&mut self, player : &Player, id : Id) {
+    if let Ok(name) = player.name() {
+      if self.by_name.get(&name) == Some(&id) {
+        self.by_name.remove(&name);
+      }
+    }
+  }
+
+  // the id of a player changes when it logs in
+  pub fn set_id(&mut self, player : &Player, id : Id) {
+    match self.remove(player) {
+      Some(p) => {
+        p.set_id(id);
+        self.add(p);
+      },
+      None => player.set_id(id)
+    }
+  }
+}
@@ -1,46 +1,325 @@
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedSender;
 use base64::encode;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
 
-use model::*;
-use utils::*;
+use crate::accounts::Accounts;
+use crate::bans::Bans;
+use crate::config::Config;
+use crate::controller::HandlerParam;
+use crate::friends::Friends;
+use crate::model::*;
+use crate::registry::PlayerRegistry;
+use crate::utils::*;
 
-type Players = Vec<Player>;
+// no 0/O or 1/I to misread when the code is told to a friend
+const ROOM_CODE_CHARS : &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const ROOM_CODE_LENGTH : usize = 6;
 
 #[derive(Debug)]
 pub struct State {
-  pub players  : Vec<Arc<Player>>,
-  pub requests : Vec<Request>
+  pub config        : Config,
+  pub players       : PlayerRegistry,
+  pub requests      : HashMap<Id, HashSet<Id>>, // duel requests by requester
+  pub requested     : HashMap<Id, HashSet<Id>>, // duel requests by requested player
+  pub duels         : HashMap<Id, Duel>, // by both duellists
+  pub private_rooms : HashMap<String, PrivateRoom>, // by code
+  pub room_codes    : HashMap<Id, String>, // by creator
+  pub lobby_lists   : HashMap<String, LobbyList>, // last published lists, dropped once empty
+  pub last_list_version : u64, // the highest version of any lobby list, new lists start from it so that versions never go back
+  pub pending_lists : HashMap<String, u64>, // lobbies with changes to broadcast, by broadcast time
+  pub subscriptions : Vec<Subscription>,
+  pub feed_queue    : VecDeque<DelayedMessage>, // ordered by release time as the delay is constant
+  pub last_ping     : u64,
+  pub friends       : Friends,
+  pub accounts      : Accounts,
+  pub bans          : Arc<Mutex<Bans>>, // shared with the listener
+  pub handler_tx    : UnboundedSender<HandlerParam> // for the outcome of work done off the handler thread
 }
 
 impl State {
 
-  pub fn new() -> State {
-    State {
-      players  : Vec::new(),
-      requests : Vec::new()
-    }
+  // a store that fails to load is not replaced by an empty one, which would be saved over it
+  pub fn new(config : Config, bans : Arc<Mutex<Bans>>, handler_tx : UnboundedSender<HandlerParam>) -> BasicResult<State> {
+    let friends_path = config.data_dir.join("friends");
+    let friends = Friends::load(friends_path.clone())
+      .map_err(|err| format!("Failed loading friends from {:?} : {}", friends_path, err))?;
+    let accounts_path = config.data_dir.join("accounts");
+    let accounts = Accounts::load(accounts_path.clone())
+      .map_err(|err| format!("Failed loading accounts from {:?} : {}", accounts_path, err))?;
+    Ok(State {
+      config,
+      players       : PlayerRegistry::new(),
+      requests      : HashMap::new(),
+      requested     : HashMap::new(),
+      duels         : HashMap::new(),
+      private_rooms : HashMap::new(),
+      room_codes    : HashMap::new(),
+      lobby_lists   : HashMap::new(),
+      last_list_version : 0,
+      pending_lists : HashMap::new(),
+      subscriptions : Vec::new(),
+      feed_queue    : VecDeque::new(),
+      last_ping     : 0,
+      friends,
+      accounts,
+      bans,
+      handler_tx
+    })
   }
 
-  pub fn add_request(&mut self, request : Request) {
-    self.requests.push(request);
+  pub fn add_request(&mut self, src_id : Id, dest_id : Id) {
+    self.requests.entry(src_id).or_default().insert(dest_id);
+    self.requested.entry(dest_id).or_default().insert(src_id);
   }
 
-  pub fn has_request(&self, id : Id) -> bool {
-    self.requests.iter().any( |r| { 
-      r.src_id == id
-    })
+  pub fn has_request(&self, src_id : Id, dest_id : Id) -> bool {
+    self.requests.get(&src_id).is_some_and(|dest_ids| dest_ids.contains(&dest_id))
   }
 
+  // drops the requests made by or to the player
   pub fn purge_request(&mut self, id : Id) {
-    self.requests.retain( |req| {
-      req.src_id != id && req.dest_id != id 
+    for dest_id in self.requests.remove(&id).unwrap_or_default() {
+      remove_from_index(&mut self.requested, dest_id, id);
+    }
+    for src_id in self.requested.remove(&id).unwrap_or_default() {
+      remove_from_index(&mut self.requests, src_id, id);
+    }
+  }
+
+  pub fn purge_request_between(&mut self, src_id : Id, dest_id : Id) {
+    remove_from_index(&mut self.requests, src_id, dest_id);
+    remove_from_index(&mut self.requested, dest_id, src_id);
+  }
+
+  pub fn start_duel(&mut self, player1 : Id, player2 : Id) -> Duel {
+    let duel = Duel { player1, player2 };
+    self.duels.insert(player1, duel);
+    self.duels.insert(player2, duel);
+    duel
+  }
+
+  pub fn duel_of(&self, id : Id) -> Option<Duel> {
+    self.duels.get(&id).cloned()
+  }
+
+  pub fn end_duel(&mut self, id : Id) -> Option<Duel> {
+    let duel = self.duels.remove(&id);
+    if let Some(ref duel) = duel {
+      self.duels.remove(&duel.other_player(id));
+    }
+    duel
+  }
+
+  // a duellist changing id on login keeps its duel
+  pub fn rename_duellist(&mut self, old_id : Id, new_id : Id) {
+    if let Some(duel) = self.duels.remove(&old_id) {
+      let other_id = duel.other_player(old_id);
+      let renamed = Duel { player1 : new_id, player2 : other_id };
+      self.duels.insert(new_id, renamed);
+      self.duels.insert(other_id, renamed);
+    }
+  }
+
+  // a creator has one room at a time, a new one replaces it
+  pub fn open_room(&mut self, room : PrivateRoom) -> String {
+    self.close_room(room.creator);
+    let mut rng = thread_rng();
+    let code = loop {
+      let code : String = (0..ROOM_CODE_LENGTH)
+        .filter_map(|_| ROOM_CODE_CHARS.choose(&mut rng).map(|&c| c as char))
+        .collect();
+      if !self.private_rooms.contains_key(&code) {
+        break code
+      }
+    };
+    self.room_codes.insert(room.creator, code.clone());
+    self.private_rooms.insert(code.clone(), room);
+    code
+  }
+
+  pub fn room(&self, code : &str) -> Option<&PrivateRoom> {
+    self.private_rooms.get(code)
+  }
+
+  pub fn close_room(&mut self, creator : Id) -> Option<PrivateRoom> {
+    self.room_codes.remove(&creator).and_then(|code| self.private_rooms.remove(&code))
+  }
+
+  pub fn is_hidden(&self, id : Id) -> bool {
+    self.room_codes.get(&id)
+      .and_then(|code| self.private_rooms.get(code))
+      .map(|room| room.hidden)
+      .unwrap_or(false)
+  }
+
+  // watching a duel again changes nothing
+  pub fn subscribe(&mut self, subscription : Subscription) {
+    if !self.subscriptions.contains(&subscription) {
+      self.subscriptions.push(subscription);
+    }
+  }
+
+  // once each, even when watching both duellists
+  pub fn observers_of(&self, duel : &Duel) -> Vec<Id> {
+    let mut observers : Vec<Id> = self.subscriptions.iter()
+      .filter(|s| s.player_id == duel.player1 || s.player_id == duel.player2)
+      .map(|s| s.observer_id)
+      .collect();
+    observers.sort_unstable();
+    observers.dedup();
+    observers
+  }
+
+  pub fn purge_subscription(&mut self, id : Id) {
+    self.subscriptions.retain( |s| {
+      s.observer_id != id && s.player_id != id
     });
   }
 
-  pub fn player_list_string(&self) -> BasicResult<String> {
-    let player_strings : Vec<String> = self.players.iter()
+  pub fn end_feed(&mut self, player_id : Id) {
+    self.subscriptions.retain( |s| s.player_id != player_id);
+  }
+
+  pub fn queue_feed(&mut self, delayed : DelayedMessage) {
+    self.feed_queue.push_back(delayed);
+  }
+
+  pub fn pop_released_feed(&mut self, now : u64) -> Vec<DelayedMessage> {
+    let mut released = Vec::new();
+    while self.feed_queue.front().map(|d| d.release_at <= now).unwrap_or(false) {
+      released.extend(self.feed_queue.pop_front());
+    }
+    released
+  }
+
+  // the listed players, creators of hidden private duels are left out
+  pub fn players_in_lobby(&self, lobby : &str) -> Vec<Arc<Player>> {
+    self.players.iter()
+      .filter(|p| p.is_in_lobby_unsafe(lobby) && !self.is_hidden(p.id())).cloned()
+      .collect()
+  }
+
+  // diffs the lobby against its last published list, each change gets the next version
+  pub fn publish_lobby(&mut self, lobby : &str) -> BasicResult<Vec<ListDelta>> {
+    let mut entries = HashMap::new();
+    for player in self.players_in_lobby(lobby) {
+      if let Some(entry) = player_string(&player)? {
+        entries.insert(player.id(), entry);
+      }
+    }
+    let last_list_version = self.last_list_version;
+    let list = self.lobby_lists.entry(lobby.to_string())
+      .or_insert_with(|| LobbyList { version : last_list_version, entries : HashMap::new() });
+    let mut changes = Vec::new();
+    for (id, entry) in entries.iter() {
+      match list.entries.get(id) {
+        None => changes.push((*id, ListChange::Joined(entry.clone()))),
+        Some(published) if listed_part(published) != listed_part(entry) =>
+          changes.push((*id, ListChange::Updated(entry.clone()))),
+        _ => ()
+      }
+    }
+    for id in list.entries.keys() {
+      if !entries.contains_key(id) {
+        changes.push((*id, ListChange::Left));
+      }
+    }
+    let deltas = changes.into_iter().map(|(id, change)| {
+      list.version += 1;
+      if let ListChange::Joined(ref entry) | ListChange::Updated(ref entry) = change {
+        list.entries.insert(id, entry.clone());
+      } else {
+        list.entries.remove(&id);
+      }
+      ListDelta { version : list.version, id, change }
+    }).collect();
+    self.last_list_version = self.last_list_version.max(list.version);
+    if list.entries.is_empty() {
+      self.lobby_lists.remove(lobby);
+    }
+    Ok(deltas)
+  }
+
+  // the first change of a lobby sets when its list is broadcast, the next ones wait along
+  pub fn queue_list_update(&mut self, lobby : &str, at : u64) {
+    self.pending_lists.entry(lobby.to_string()).or_insert(at);
+  }
+
+  pub fn pop_due_lists(&mut self, now : u64) -> Vec<String> {
+    let due : Vec<String> = self.pending_lists.iter()
+      .filter(|&(_, at)| *at <= now)
+      .map(|(lobby, _)| lobby.clone())
+      .collect();
+    for lobby in due.iter() {
+      self.pending_lists.remove(lobby);
+    }
+    due
+  }
+
+  pub fn lobby_version(&self, lobby : &str) -> u64 {
+    self.lobby_lists.get(lobby).map(|list| list.version).unwrap_or(self.last_list_version)
+  }
+
+  // version;player strings of the last published list
+  pub fn lobby_snapshot_string(&self, lobby : &str, hidden : &HashSet<Id>) -> String {
+    let mut parts = vec![self.lobby_version(lobby).to_string()];
+    if let Some(list) = self.lobby_lists.get(lobby) {
+      parts.extend(list.entries.iter()
+        .filter(|&(id, _)| !hidden.contains(id))
+        .map(|(_, entry)| entry.clone()));
+    }
+    parts.join(";")
+  }
+
+  // base64 lobby name:number of players, the default lobby is always listed
+  pub fn lobby_list_string(&self) -> BasicResult<String> {
+    let mut lobbies : BTreeMap<String, usize> = BTreeMap::new();
+    lobbies.insert(DEFAULT_LOBBY.to_string(), 0);
+    for player in self.players.iter() {
+      *lobbies.entry(player.lobby()?).or_insert(0) += 1;
+    }
+    let lobby_strings : Vec<String> = lobbies.iter()
+      .map(|(lobby, count)| format!("{}:{}", encode(lobby.as_bytes()), count))
+      .collect();
+    Ok(lobby_strings.join(";"))
+  }
+
+  // total of the matching players;the requested page of them
+  pub fn query_player_list_string(&self, lobby : &str, query : &ListQuery, hidden : &HashSet<Id>) -> BasicResult<String> {
+    let mut matching = Vec::new();
+    for player in self.players_in_lobby(lobby) {
+      let state = box_err(player.state.read())?.clone();
+      let listed = !state.name.is_empty() && !hidden.contains(&player.id())
+        && query.on_hold.map(|on_hold| on_hold == matches!(state.status, PlayerStatus::OnHold)).unwrap_or(true)
+        && query.name_prefix.as_ref().map(|prefix| state.name.to_lowercase().starts_with(&prefix.to_lowercase())).unwrap_or(true);
+      if listed {
+        matching.push((player.id(), state));
+      }
+    }
+    match query.sort {
+      ListSort::Id => matching.sort_by_key(|&(id, _)| id),
+      ListSort::Name => matching.sort_by(|a, b| a.1.name.cmp(&b.1.name).then(a.0.cmp(&b.0))),
+      ListSort::Rtt => matching.sort_by_key(|(id, state)| (state.rtt, *id))
+    }
+    if query.descending {
+      matching.reverse();
+    }
+    let mut parts = vec![matching.len().to_string()];
+    parts.extend(matching.iter()
+      .skip(query.offset)
+      .take(query.limit)
+      .map(|(id, state)| format_player(*id, state)));
+    Ok(parts.join(";"))
+  }
+
+  pub fn player_list_string(&self, lobby : &str, hidden : &HashSet<Id>) -> BasicResult<String> {
+    let player_strings : Vec<String> = self.players_in_lobby(lobby).iter()
+      .filter(|player| !hidden.contains(&player.id()))
       .filter_map(|player| {
-        match player_string(&player) { // FIXME
+        match player_string(player) { // FIXME
           Ok(name) => name,
           Err(err) => {
             warn!("Failed getting name {}", err); 
@@ -53,22 +332,59 @@ impl State {
   }
 }
 
+// the list of a lobby as last sent to the players taking deltas
+#[derive(Debug)]
+pub struct LobbyList {
+  pub version : u64,
+  pub entries : HashMap<Id, String> // player strings
+}
+
+// a rtt change alone is not worth an update, it changes at each ping
+fn listed_part(entry : &str) -> &str {
+  entry.rsplit_once(':').map(|(listed, _)| listed).unwrap_or(entry)
+}
+
+pub fn find_player(id : Id, state : &State) -> Option<Arc<Player>> {
+  state.players.get(id).cloned()
+}
+
+pub fn find_players_by_name(name : &str, state : &State) -> Vec<Arc<Player>> {
+  state.players.named(name).cloned().into_iter().collect()
+}
+
 pub fn find_player_on_hold(id : Id, state : &State) -> Option<Arc<Player>> {
-  state.players.iter()
-    .find(|&p| { p.id == id && p.is_on_hold_unsafe() })
-    .map(|p| p.clone())
+  state.players.get_on_hold(id).cloned()
+}
+
+// keeps no empty entry so that a key means at least one request
+fn remove_from_index(index : &mut HashMap<Id, HashSet<Id>>, key : Id, id : Id) {
+  let now_empty = match index.get_mut(&key) {
+    Some(ids) => {
+      ids.remove(&id);
+      ids.is_empty()
+    },
+    None => false
+  };
+  if now_empty {
+    index.remove(&key);
+  }
 }
 
 
-fn player_string(player : &Player) -> BasicResult<Option<String>> {
-  let state = try!(box_err(player.state.read()));
+pub fn player_string(player : &Player) -> BasicResult<Option<String>> {
+  let state = box_err(player.state.read())?;
   if state.name.is_empty() {
     Ok(None)
   } else {
-    let status = match state.status { // crap
-      PlayerStatus::OnHold => 0,
-      _ => 1
-    };
-    Ok(Some(format!("{}:{}:{}", encode(state.name.as_bytes()) , status, player.id)))
+    Ok(Some(format_player(player.id(), &state)))
   }
 }
+
+// base64 name:status:id:rtt
+fn format_player(id : Id, state : &PlayerState) -> String {
+  let status = match state.status { // crap
+    PlayerStatus::OnHold => 0,
+    _ => 1
+  };
+  format!("{}:{}:{}:{}", encode(state.name.as_bytes()), status, id, state.rtt)
+}
@@ -1,25 +1,58 @@
-use std::io;
+use std::io::{self, Write};
 use std::error::Error;
 use std::fmt::Display;
+use std::fs::{self, File};
+use std::path::Path;
 
-pub type BasicResult<A> = Result<A, Box<Error>>;
+pub type BasicResult<A> = Result<A, Box<dyn Error>>;
+
+// shown instead of a secret in the debug output
+pub const REDACTED : &str = "<redacted>";
 
 pub fn box_err<A, B : Display>(x : Result<A, B>) -> BasicResult<A> {
   x.map_err(|err| From::from(err.to_string()))
 }
 
 pub fn io_err(message : &str) -> io::Error {
-  io::Error::new(io::ErrorKind::Other, message)
+  io::Error::other(message)
 }
 
 pub fn map_io_err<A, B : Display>(x : Result<A, B>) -> Result<A, io::Error> {
   x.map_err(|err| io_err(&err.to_string()))
 }
 
-pub fn check_slice<A>(buf : &[A], start : usize, end : usize) -> Result<&[A], io::Error> {
-  if start > buf.len() || end > buf.len() {
-    Err(io_err(&format!("Over bounds [{}..{}]", start, end)))
-  } else {
-    Ok(&buf[start..end])
+// written aside then renamed, so that a crash never leaves a truncated file
+pub fn write_atomically(path : &Path, contents : &str) -> io::Result<()> {
+  let tmp_path = path.with_extension("tmp");
+  {
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+  }
+  fs::rename(&tmp_path, path)
+}
+
+pub fn constant_time_eq(a : &[u8], b : &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// a directory under the system temp dir, removed with everything in it when dropped
+#[cfg(test)]
+pub struct TempDir(pub std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+
+  pub fn new() -> TempDir {
+    let path = std::env::temp_dir().join(format!("fserve-test-{}", rand::random::<u64>()));
+    fs::create_dir_all(&path).unwrap();
+    TempDir(path)
+  }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.0);
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,168 @@
+use std::collections::VecDeque;
+use std::io::{self, IoSlice};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use crate::model::*;
+
+// upper bound of messages gathered in one vectored write
+const MAX_BATCH : usize = 64;
+
+struct Pending {
+  header : Vec<u8>, // serialized header line
+  msg    : Arc<Message>
+}
+
+impl Pending {
+
+  fn len(&self) -> usize {
+    self.header.len() + self.msg.body.len()
+  }
+}
+
+// messages waiting to be written to a connection, the front one may be partially written
+pub struct Writer {
+  pending : VecDeque<Pending>,
+  offset  : usize // bytes of the front message already written
+}
+
+impl Writer {
+
+  pub fn new() -> Writer {
+    Writer {
+      pending : VecDeque::new(),
+      offset  : 0
+    }
+  }
+
+  pub fn queue(&mut self, msg : Arc<Message>) {
+    let mut header = msg.header.to_string().into_bytes();
+    header.push(b'\n');
+    self.pending.push_back(Pending { header, msg });
+  }
+
+  pub fn has_pending(&self) -> bool {
+    !self.pending.is_empty()
+  }
+
+  // writes as much as the socket accepts without blocking
+  pub fn flush(&mut self, conn : &TcpStream) -> io::Result<()> {
+    while self.has_pending() {
+      let written = {
+        let slices = self.slices();
+        match conn.try_write_vectored(&slices) {
+          Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "Connection closed while writing")),
+          Ok(n) => n,
+          Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+          Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+          Err(e) => return Err(e)
+        }
+      };
+      self.advance(written);
+    }
+    Ok(())
+  }
+
+  // writes everything, waiting for the socket when it is full but no longer than limit
+  pub async fn flush_all(&mut self, conn : &TcpStream, limit : Duration) -> io::Result<()> {
+    match timeout(limit, self.flush_waiting(conn)).await {
+      Ok(result) => result,
+      Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "Peer stopped reading"))
+    }
+  }
+
+  async fn flush_waiting(&mut self, conn : &TcpStream) -> io::Result<()> {
+    loop {
+      self.flush(conn)?;
+      if !self.has_pending() {
+        return Ok(())
+      }
+      conn.writable().await?;
+    }
+  }
+
+  // the unwritten parts of the first MAX_BATCH messages
+  fn slices(&self) -> Vec<IoSlice<'_>> {
+    let mut slices = Vec::with_capacity(2 * MAX_BATCH);
+    let mut skip = self.offset;
+    for pending in self.pending.iter().take(MAX_BATCH) {
+      for part in [&pending.header[..], &pending.msg.body[..]].iter() {
+        if skip >= part.len() {
+          skip -= part.len();
+        } else {
+          slices.push(IoSlice::new(&part[skip..]));
+          skip = 0;
+        }
+      }
+    }
+    slices
+  }
+
+  fn advance(&mut self, mut written : usize) {
+    while written > 0 {
+      let remaining = match self.pending.front() {
+        Some(pending) => pending.len() - self.offset,
+        None => return
+      };
+      if written >= remaining {
+        written -= remaining;
+        self.offset = 0;
+        if let Some(pending) = self.pending.pop_front() {
+          debug!("Sent {:?}", pending.msg.header);
+        }
+      } else {
+        self.offset += written;
+        written = 0;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::net::TcpListener;
+  use super::*;
+
+  fn unwritten(writer : &Writer) -> String {
+    let bytes : Vec<u8> = writer.slices().iter().flat_map(|slice| slice.iter().cloned()).collect();
+    String::from_utf8(bytes).unwrap()
+  }
+
+  #[test]
+  fn advance_resumes_within_header_and_body() {
+    let mut writer = Writer::new();
+    writer.queue(Arc::new(Message::new(MessageType::LobbyChat, "hello")));
+    writer.queue(Arc::new(Message::new(MessageType::LobbyChat, "bye")));
+    assert_eq!(unwritten(&writer), "9;5;0;0\nhello9;3;0;0\nbye");
+    writer.advance(3);
+    assert_eq!(unwritten(&writer), ";0;0\nhello9;3;0;0\nbye");
+    // from the header into the body
+    writer.advance(6);
+    assert_eq!(unwritten(&writer), "ello9;3;0;0\nbye");
+    // from the body into the next header
+    writer.advance(6);
+    assert_eq!(unwritten(&writer), "3;0;0\nbye");
+    assert_eq!(writer.pending.len(), 1);
+    writer.advance(7);
+    assert_eq!(unwritten(&writer), "ye");
+    writer.advance(2);
+    assert!(!writer.has_pending());
+    assert_eq!(writer.offset, 0);
+  }
+
+  #[tokio::test]
+  async fn flush_all_gives_up_on_a_peer_not_reading() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let conn = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let _peer = listener.accept().await.unwrap();
+    let mut writer = Writer::new();
+    let body = "x".repeat(1 << 20);
+    for _ in 0..64 {
+      writer.queue(Arc::new(Message::new(MessageType::Proxy, &body)));
+    }
+    let err = writer.flush_all(&conn, Duration::from_millis(100)).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(writer.has_pending());
+  }
+}
@@ -6,20 +6,47 @@ use std::{thread, time};
 
 #[test]
 fn it_works() {
-    let server_handle = thread::spawn(|| {
+    // the server never returns, it is dropped with the test process
+    thread::spawn(|| {
         fserve::run_server();
     });
-    assert_eq!(1, 1);
-    
-    let client_handle = thread::spawn(|| {
-        thread::sleep(time::Duration::from_secs(2));
-        let mut stream = TcpStream::connect("127.0.0.1:12345").unwrap();
 
-        // ignore the Result
-        let _ = stream.write("1;4;0;0\ntoto".as_bytes());
-        thread::sleep(time::Duration::from_secs(2));
-    });
+    let mut stream = None;
+    for _ in 0..50 {
+        match TcpStream::connect("127.0.0.1:12345") {
+            Ok(s) => {
+                stream = Some(s);
+                break
+            },
+            Err(_) => thread::sleep(time::Duration::from_millis(100))
+        }
+    }
+    let mut stream = stream.expect("server not listening");
+    stream.set_read_timeout(Some(time::Duration::from_secs(5))).unwrap();
+
+    let welcome = b"0;18;0;0\nWelcome apprentice";
+    let mut buf = vec![0u8; welcome.len()];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..], &welcome[..]);
 
-    client_handle.join().unwrap();
-    server_handle.join().unwrap();
-}
\ No newline at end of file
+    stream.write_all("1;4;0;0\ntoto".as_bytes()).unwrap();
+    stream.write_all("7;0;1;0\n".as_bytes()).unwrap();
+    // list broadcasts may come first, the answer refers to the request message id
+    loop {
+        let mut header = String::new();
+        let mut byte = [0u8; 1];
+        while !header.ends_with('\n') {
+            stream.read_exact(&mut byte).unwrap();
+            header.push(byte[0] as char);
+        }
+        let fields : Vec<&str> = header.trim_end().split(';').collect();
+        assert_eq!(fields.len(), 4, "unexpected header {}", header);
+        let mut body = vec![0u8; fields[1].parse().unwrap()];
+        stream.read_exact(&mut body).unwrap();
+        if fields[3] == "1" {
+            assert_eq!(fields[0], "7");
+            assert!(String::from_utf8(body).unwrap().contains(":0:"));
+            break
+        }
+    }
+}